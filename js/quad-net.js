@@ -9,9 +9,10 @@ register_plugin = function (importObject) {
 
     importObject.env.http_make_request = http_make_request;
     importObject.env.http_try_recv = http_try_recv;
+    importObject.env.http_abort = http_abort;
 }
 
-miniquad_add_plugin({ register_plugin, on_init, version: 1, name: "quad_net" });
+miniquad_add_plugin({ register_plugin, on_init, version: 2, name: "quad_net" });
 
 var quad_socket;
 var connected = 0;
@@ -27,6 +28,9 @@ function ws_connect(addr) {
     quad_socket.onopen = function() {
         connected = 1;
     };
+    quad_socket.onclose = function() {
+        connected = 0;
+    };
 
     quad_socket.onmessage = function(msg) {
         if (typeof msg.data == "string") {
@@ -65,6 +69,7 @@ function ws_try_recv() {
 
 var uid = 0;
 var ongoing_requests = {};
+var ongoing_xhrs = {};
 
 function http_try_recv(cid) {
     if (ongoing_requests[cid] != undefined && ongoing_requests[cid] != null) {
@@ -75,7 +80,15 @@ function http_try_recv(cid) {
     return -1;
 }
 
-function http_make_request(scheme, url, body, headers) {
+function http_abort(cid) {
+    if (ongoing_xhrs[cid] != undefined) {
+        ongoing_xhrs[cid].abort();
+    }
+    delete ongoing_xhrs[cid];
+    delete ongoing_requests[cid];
+}
+
+function http_make_request(scheme, url, body, headers, timeout, with_credentials) {
     var cid = uid;
 
     uid += 1;
@@ -93,29 +106,56 @@ function http_make_request(scheme, url, body, headers) {
     if (scheme == 3) {
         scheme_string = 'DELETE';
     }
+    if (scheme == 4) {
+        scheme_string = 'PATCH';
+    }
+    if (scheme == 5) {
+        scheme_string = 'HEAD';
+    }
+    if (scheme == 6) {
+        scheme_string = 'OPTIONS';
+    }
     var url_string = consume_js_object(url);
-    var body_string = consume_js_object(body);
+    var body_buffer = consume_js_object(body);
     var headers_obj = consume_js_object(headers);
+    var started = performance.now();
     var xhr = new XMLHttpRequest();
     xhr.open(scheme_string, url_string, true);
     xhr.responseType = 'arraybuffer';
+    xhr.timeout = timeout;
+    xhr.withCredentials = with_credentials == 1;
     for (const header in headers_obj) {
         xhr.setRequestHeader(header, headers_obj[header]);
     }
+    xhr.onloadend = function (e) {
+        delete ongoing_xhrs[cid];
+    }
     xhr.onload = function (e) {
-        if (this.status == 200) {
-            var uInt8Array = new Uint8Array(this.response);
-            
-            ongoing_requests[cid] = uInt8Array;
-        }
+        var uInt8Array = new Uint8Array(this.response);
+
+        ongoing_requests[cid] = {
+            "status": this.status,
+            "reason": this.statusText,
+            "headers": this.getAllResponseHeaders(),
+            "body": uInt8Array,
+            "url": this.responseURL || url_string,
+            "elapsed": Math.round(performance.now() - started)
+        };
+    }
+    xhr.ontimeout = function (e) {
+        ongoing_requests[cid] = {
+            "error": 1
+        };
     }
+    // DNS, CORS, refused connections and the like, the browser tells nothing more
     xhr.onerror = function (e) {
-        // todo: let rust know and put Error to ongoing requests
-        console.error("Failed to make a request");
-        console.error(e);
+        ongoing_requests[cid] = {
+            "error": 2
+        };
     };
 
-    xhr.send(body_string);
+    ongoing_xhrs[cid] = xhr;
+    xhr.send(body_buffer);
 
     return cid;
 }
@@ -1,6 +1,46 @@
+use crate::http_request::HttpError;
+
+/// The error of the whole crate: socket errors directly, HTTP errors wrapped,
+/// so both can be `?`-ed into the same function.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     IOError(std::io::Error),
+    /// The message is longer than its frame format allows, see
+    /// `FrameFormat::max_message_size`: 16 MiB by default, less with shorter prefixes.
+    MessageTooLarge,
+    /// Received bytes that were not a UTF-8 string.
+    NotStrError,
+    /// A typed message could not be serialized or deserialized.
+    #[cfg(feature = "serde")]
+    JsonError(serde_json::Error),
+    /// From `http_request`.
+    HttpError(HttpError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IOError(error) => write!(f, "IO error: {error}"),
+            Error::MessageTooLarge => write!(f, "Message exceeded the size limit"),
+            Error::NotStrError => write!(f, "Received bytes that were not a string"),
+            #[cfg(feature = "serde")]
+            Error::JsonError(error) => write!(f, "Json error: {error}"),
+            Error::HttpError(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(error) => Some(error),
+            #[cfg(feature = "serde")]
+            Error::JsonError(error) => Some(error),
+            Error::HttpError(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -8,3 +48,16 @@ impl From<std::io::Error> for Error {
         Error::IOError(error)
     }
 }
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::JsonError(error)
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(error: HttpError) -> Error {
+        Error::HttpError(error)
+    }
+}
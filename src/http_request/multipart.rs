@@ -0,0 +1,155 @@
+//! `multipart/form-data` bodies, see `RequestBuilder::multipart`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fields of a `multipart/form-data` body, sent in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct MultipartBuilder {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+struct Part {
+    name: String,
+    /// File name and content type, for file fields.
+    file: Option<(String, String)>,
+    data: Vec<u8>,
+}
+
+/// Makes every boundary unique, on top of checking it against the data.
+static BOUNDARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl MultipartBuilder {
+    pub fn new() -> MultipartBuilder {
+        MultipartBuilder::default()
+    }
+
+    /// Plain text field.
+    pub fn text(mut self, name: &str, value: &str) -> MultipartBuilder {
+        self.parts.push(Part {
+            name: name.to_owned(),
+            file: None,
+            data: value.as_bytes().to_vec(),
+        });
+
+        MultipartBuilder { parts: self.parts }
+    }
+
+    /// File field, like `.file("avatar", "me.png", "image/png", png)`.
+    pub fn file(
+        mut self,
+        name: &str,
+        filename: &str,
+        content_type: &str,
+        bytes: Vec<u8>,
+    ) -> MultipartBuilder {
+        self.parts.push(Part {
+            name: name.to_owned(),
+            file: Some((filename.to_owned(), content_type.to_owned())),
+            data: bytes,
+        });
+
+        MultipartBuilder { parts: self.parts }
+    }
+
+    /// `Content-Type` header and body of the form.
+    pub(super) fn build(&self) -> (String, Vec<u8>) {
+        let boundary = self.boundary();
+        let mut body = vec![];
+
+        for part in &self.parts {
+            body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+            body.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"",
+                    escape(&part.name)
+                )
+                .as_bytes(),
+            );
+            if let Some((filename, content_type)) = &part.file {
+                body.extend_from_slice(
+                    format!(
+                        "; filename=\"{}\"\r\nContent-Type: {}",
+                        escape(filename),
+                        content_type
+                    )
+                    .as_bytes(),
+                );
+            }
+            body.extend_from_slice(b"\r\n\r\n");
+            body.extend_from_slice(&part.data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+        (format!("multipart/form-data; boundary={boundary}"), body)
+    }
+
+    /// Boundary that does not show up in any of the fields.
+    fn boundary(&self) -> String {
+        loop {
+            let boundary = format!(
+                "quad-net-boundary-{:016x}",
+                BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+
+            let collides = self
+                .parts
+                .iter()
+                .any(|part| contains(&part.data, boundary.as_bytes()));
+            if !collides {
+                return boundary;
+            }
+        }
+    }
+}
+
+/// Percent-encode what would end the quoted name early, the same browsers do.
+fn escape(name: &str) -> String {
+    name.replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_framed_with_crlf_and_names_escaped() {
+        let data = vec![0, 255, b'\r', b'\n', b'-', b'-'];
+        let form = MultipartBuilder::new()
+            .text("title", "a \"quoted\" value")
+            .file(
+                "upload",
+                "my \"file\".bin",
+                "application/octet-stream",
+                data.clone(),
+            );
+
+        let (content_type, body) = form.build();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+
+        let mut expected = format!(
+            "--{boundary}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\
+             \r\n\
+             a \"quoted\" value\r\n\
+             --{boundary}\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"my %22file%22.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\
+             \r\n"
+        )
+        .into_bytes();
+        expected.extend_from_slice(&data);
+        expected.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        assert_eq!(body, expected);
+    }
+}
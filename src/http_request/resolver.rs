@@ -0,0 +1,54 @@
+//! Hosts pinned to an address, see `Client::resolve`.
+
+use std::net::SocketAddr;
+
+use ureq::{
+    config::Config,
+    http::Uri,
+    unversioned::{
+        resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver},
+        transport::NextTimeout,
+    },
+    Error,
+};
+
+/// Looks up every host that is not pinned as usual.
+#[derive(Debug)]
+pub(super) struct PinnedResolver {
+    pins: Vec<(String, SocketAddr)>,
+    fallback: DefaultResolver,
+}
+
+impl PinnedResolver {
+    pub(super) fn new(pins: Vec<(String, SocketAddr)>) -> PinnedResolver {
+        PinnedResolver {
+            pins,
+            fallback: DefaultResolver::default(),
+        }
+    }
+}
+
+impl Resolver for PinnedResolver {
+    fn resolve(
+        &self,
+        uri: &Uri,
+        config: &Config,
+        timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, Error> {
+        let host = uri.host().unwrap_or_default();
+        let pinned = self
+            .pins
+            .iter()
+            .find(|(pinned, _)| pinned.eq_ignore_ascii_case(host));
+
+        match pinned {
+            Some((_, addr)) => {
+                let mut addrs = self.empty();
+                addrs.push(*addr);
+
+                Ok(addrs)
+            }
+            None => self.fallback.resolve(uri, config, timeout),
+        }
+    }
+}
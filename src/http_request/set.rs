@@ -0,0 +1,91 @@
+//! Many requests polled as one, see `RequestSet`.
+
+use super::{HttpError, Request};
+
+/// Identifies a request in its `RequestSet`, ids are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+/// Requests in flight at the same time, like the assets of a level.
+///
+/// ```ignore
+/// let mut set = RequestSet::new();
+/// let manifest = set.push(RequestBuilder::new("https://example.com/level.json").send());
+///
+/// // Every frame
+/// for (id, body) in set.poll() {
+///     ...
+/// }
+/// if set.is_empty() {
+///     // All loaded
+/// }
+/// ```
+#[derive(Default)]
+pub struct RequestSet {
+    next_id: u64,
+    requests: Vec<(RequestId, Request)>,
+}
+
+impl RequestSet {
+    pub fn new() -> RequestSet {
+        RequestSet::default()
+    }
+
+    /// Start tracking `request`, which may already be done.
+    pub fn push(&mut self, request: Request) -> RequestId {
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.requests.push((id, request));
+
+        id
+    }
+
+    /// The bodies of the requests that completed since the last call, in the order
+    /// they were pushed. They are no longer tracked afterwards.
+    ///
+    /// Requests whose body was already received outside of the set are dropped
+    /// without showing up here.
+    pub fn poll(&mut self) -> Vec<(RequestId, Result<Vec<u8>, HttpError>)> {
+        let mut completed = vec![];
+
+        self.requests.retain_mut(|(id, request)| {
+            if !request.is_done() {
+                return true;
+            }
+            if let Some(body) = request.try_recv_bytes() {
+                completed.push((*id, body));
+            }
+            false
+        });
+
+        completed
+    }
+
+    /// Stop tracking the request and hand it back, dropping it cancels it. `None` if
+    /// it was already handed out by `poll`.
+    pub fn remove(&mut self, id: RequestId) -> Option<Request> {
+        let index = self
+            .requests
+            .iter()
+            .position(|(request_id, _)| *request_id == id)?;
+
+        Some(self.requests.remove(index).1)
+    }
+
+    /// Whether the request is still in flight, or done but not yet handed out by `poll`.
+    pub fn contains(&self, id: RequestId) -> bool {
+        self.requests
+            .iter()
+            .any(|(request_id, _)| *request_id == id)
+    }
+
+    /// Number of requests not yet handed out by `poll`.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether every request was handed out by `poll`, the whole batch is done.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
@@ -0,0 +1,189 @@
+//! Plain HTTP/1.1 over a Unix domain socket, see `RequestBuilder::unix_socket`.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time,
+};
+
+use ureq::{
+    config::Config,
+    http::Uri,
+    unversioned::{
+        resolver::{ResolvedSocketAddrs, Resolver},
+        transport::{Buffers, ConnectionDetails, Connector, LazyBuffers, NextTimeout, Transport},
+    },
+    Error,
+};
+
+/// Agent that sends every request to the socket at `path`, whatever the url says.
+pub(super) fn agent(config: Config, path: PathBuf) -> ureq::Agent {
+    ureq::Agent::with_parts(config, UnixConnector { path }, NoResolver)
+}
+
+#[derive(Debug)]
+struct UnixConnector {
+    path: PathBuf,
+}
+
+impl Connector<()> for UnixConnector {
+    type Out = UnixTransport;
+
+    fn connect(
+        &self,
+        details: &ConnectionDetails,
+        _chained: Option<()>,
+    ) -> Result<Option<UnixTransport>, Error> {
+        let stream = UnixStream::connect(&self.path)?;
+        let config = details.config;
+
+        Ok(Some(UnixTransport {
+            stream,
+            buffers: LazyBuffers::new(config.input_buffer_size(), config.output_buffer_size()),
+            timeout_read: None,
+            timeout_write: None,
+        }))
+    }
+}
+
+/// The host of the url is only the `Host` header, there is nothing to resolve.
+#[derive(Debug)]
+struct NoResolver;
+
+impl Resolver for NoResolver {
+    fn resolve(
+        &self,
+        _uri: &Uri,
+        _config: &Config,
+        _timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, Error> {
+        // ureq wants at least one address, the connector never looks at it
+        let mut addrs = self.empty();
+        addrs.push(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+
+        Ok(addrs)
+    }
+}
+
+#[derive(Debug)]
+struct UnixTransport {
+    stream: UnixStream,
+    buffers: LazyBuffers,
+    /// Set on the stream, so it is only changed when it has to be.
+    timeout_read: Option<time::Duration>,
+    timeout_write: Option<time::Duration>,
+}
+
+impl Transport for UnixTransport {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        &mut self.buffers
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), Error> {
+        let wanted = timeout.not_zero().map(|timeout| *timeout);
+        if wanted != self.timeout_write {
+            self.stream.set_write_timeout(wanted)?;
+            self.timeout_write = wanted;
+        }
+
+        let output = &self.buffers.output()[..amount];
+        self.stream
+            .write_all(output)
+            .map_err(|err| timed_out(err, timeout))
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, Error> {
+        let wanted = timeout.not_zero().map(|timeout| *timeout);
+        if wanted != self.timeout_read {
+            self.stream.set_read_timeout(wanted)?;
+            self.timeout_read = wanted;
+        }
+
+        let input = self.buffers.input_append_buf();
+        let amount = self
+            .stream
+            .read(input)
+            .map_err(|err| timed_out(err, timeout))?;
+        self.buffers.input_appended(amount);
+
+        Ok(amount > 0)
+    }
+
+    fn is_open(&mut self) -> bool {
+        // Anything to read, or the end of the stream, means the connection
+        // can't be used for another request
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let open = matches!(
+            self.stream.read(&mut [0]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock
+        );
+
+        open && self.stream.set_nonblocking(false).is_ok()
+    }
+}
+
+/// Read and write timeouts show up as `WouldBlock` on some platforms.
+fn timed_out(err: io::Error, timeout: NextTimeout) -> Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout(timeout.reason),
+        _ => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::net::UnixListener,
+    };
+
+    use super::*;
+    use crate::http_request::RequestBuilder;
+
+    #[test]
+    fn requests_go_to_the_socket_with_the_host_of_the_url() {
+        let dir = std::env::temp_dir().join(format!("quad-net-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("http.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Request line and headers of the one request it answers
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut lines = vec![];
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                lines.push(line.trim_end().to_owned());
+                line.clear();
+            }
+
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+                )
+                .unwrap();
+            lines
+        });
+
+        let body = RequestBuilder::new("http://api.example/v1/status")
+            .unix_socket(&path)
+            .send()
+            .recv_str()
+            .unwrap();
+        let lines = server.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(body, "hello");
+        assert_eq!(lines[0], "GET /v1/status HTTP/1.1");
+        let host = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("host: ").or(line.strip_prefix("Host: ")));
+        assert_eq!(host, Some("api.example"));
+    }
+}
@@ -5,11 +5,16 @@ extern crate qws as ws;
 
 mod error;
 
+pub use error::Error;
+
 pub mod http_request;
 pub mod quad_socket;
 pub mod web_socket;
 
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_tls;
+
 #[no_mangle]
 pub extern "C" fn quad_net_crate_version() -> u32 {
-    1
+    2
 }
@@ -11,5 +11,4 @@ pub mod client;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod server;
 
-#[cfg(not(target_arch = "wasm32"))]
-mod protocol;
+pub mod protocol;
@@ -2,9 +2,25 @@ use std::net::ToSocketAddrs;
 
 #[cfg(not(target_arch = "wasm32"))]
 mod tcp;
+#[cfg(not(target_arch = "wasm32"))]
+mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+mod udp;
+
 use crate::error::Error;
 #[cfg(target_arch = "wasm32")]
 use crate::web_socket::js_web_socket as websocket;
+/// The same WebSocket on web and native, for talking to WebSocket servers directly.
+pub use crate::web_socket::WebSocket;
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::{
+    ConnectionStatus, Heartbeat, PendingConnection, ReconnectPolicy, SocketOptions, SocketStats,
+    TcpReader, TcpSocket, TcpWriter, WhenFull,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use tls::TlsSocket;
+#[cfg(not(target_arch = "wasm32"))]
+pub use udp::UdpSocket;
 
 pub struct QuadSocket {
     #[cfg(not(target_arch = "wasm32"))]
@@ -14,15 +30,16 @@ pub struct QuadSocket {
 }
 
 impl QuadSocket {
-    pub fn send(&mut self, data: &[u8]) {
+    /// Fails once the connection is gone, on native.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
         #[cfg(not(target_arch = "wasm32"))]
         {
-            self.tcp_socket.send(data);
+            self.tcp_socket.send(data)
         }
 
         #[cfg(target_arch = "wasm32")]
         {
-            self.web_socket.send_bytes(data);
+            self.web_socket.send_bytes(data)
         }
     }
 
@@ -37,14 +54,19 @@ impl QuadSocket {
             self.web_socket.try_recv()
         }
     }
+
+    /// Every message received so far, never blocks.
+    pub fn try_recv_all(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        std::iter::from_fn(move || self.try_recv())
+    }
 }
 
 #[cfg(feature = "nanoserde")]
 impl QuadSocket {
-    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) {
+    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) -> Result<(), Error> {
         use nanoserde::SerBin;
 
-        self.send(&SerBin::serialize_bin(data));
+        self.send(&SerBin::serialize_bin(data))
     }
 
     pub fn try_recv_bin<T: nanoserde::DeBin + std::fmt::Debug>(&mut self) -> Option<T> {
@@ -61,6 +83,22 @@ impl QuadSocket {
         self.web_socket.connected()
     }
 
+    /// `false` once the connection was closed or broke, messages that arrived
+    /// before are still received by `try_recv`.
+    ///
+    /// On wasm it is also `false` until the WebSocket finished connecting.
+    pub fn is_connected(&self) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.tcp_socket.is_connected()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.web_socket.connected()
+        }
+    }
+
     pub fn connect<A: ToSocketAddrs + std::fmt::Display>(addr: A) -> Result<QuadSocket, Error> {
         Ok(QuadSocket {
             #[cfg(not(target_arch = "wasm32"))]
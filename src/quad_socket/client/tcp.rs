@@ -1,47 +1,1556 @@
 use std::{
-    net::{TcpStream, ToSocketAddrs},
-    sync::mpsc::{self, Receiver},
+    collections::VecDeque,
+    io::Write,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{error::Error, quad_socket::protocol::MessageReader};
+use crate::{
+    error::Error,
+    quad_socket::protocol::{
+        write_ping, write_pong, write_resume, Frame, FrameFormat, MessageReader, MAX_MESSAGE_SIZE,
+    },
+};
+
+/// How long `connect_any` waits for an address before also trying the next one.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Most buffers kept around for `TcpSocket::try_recv_into`, enough for a burst
+/// of messages without holding on to a lot of memory.
+const FREE_BUFFERS: usize = 32;
+
+/// Whether a `TcpSocket` can send and receive right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// Lost the connection and dialing again, see `TcpSocket::connect_with_reconnect`.
+    Reconnecting,
+    /// Closed by the server or broken, for good.
+    Disconnected,
+}
+
+/// How `TcpSocket::connect_with_reconnect` gets a lost connection back.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Give up after this many failed attempts in a row, `None` to keep trying.
+    pub max_attempts: Option<u32>,
+    /// Wait before the first attempt, doubled after every failed one.
+    pub backoff: Duration,
+    /// Longest wait between two attempts.
+    pub max_backoff: Duration,
+    /// Keep messages sent while reconnecting and send them once connected again,
+    /// instead of failing `send`.
+    pub queue_while_reconnecting: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(10),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            queue_while_reconnecting: true,
+        }
+    }
+}
+
+/// What the reader thread does with a message while `receive_capacity` messages
+/// are already waiting to be received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Make room by dropping the oldest waiting message.
+    DropOldest,
+    /// Drop the message that just arrived.
+    DropNewest,
+    /// Stop reading until there is room, so the server has to slow down.
+    Pause,
+}
+
+/// Pings sent by the reader thread, to notice a connection that died without
+/// being closed, like after a NAT timeout or a laptop going to sleep.
+///
+/// The server has to answer them, `quad_socket::server` does.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    /// Ping this often.
+    pub interval: Duration,
+    /// The connection counts as lost after this long without receiving anything.
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// How `TcpSocket::connect_with_options` sets up the connection.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// Send every message right away instead of batching small ones (Nagle's algorithm).
+    /// `true` by default, which is what games want, bulk transfers are better off without.
+    pub nodelay: bool,
+    /// Size of the OS receive buffer, `None` for the system default.
+    pub read_buffer_size: Option<usize>,
+    /// Size of the OS send buffer, `None` for the system default.
+    pub write_buffer_size: Option<usize>,
+    /// Give up connecting after this long, `None` to leave it to the OS.
+    /// The addresses a host resolved to are tried in turn within this time.
+    pub connect_timeout: Option<Duration>,
+    /// Dial again whenever the connection is lost, see `TcpSocket::connect_with_reconnect`.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// How many received messages may wait for `try_recv`, `None` for no limit.
+    /// `Some(0)` is the same as `Some(1)`.
+    pub receive_capacity: Option<usize>,
+    /// What happens to messages beyond `receive_capacity`, `Pause` by default.
+    pub when_full: WhenFull,
+    /// The connection is dropped when the server announces a larger message,
+    /// rather than allocating it. `MAX_MESSAGE_SIZE` by default.
+    pub max_message_size: usize,
+    /// Ping the server to find out when the connection silently died, `None` by default.
+    ///
+    /// Needs the default `frame_format`, the others have no pings.
+    pub heartbeat: Option<Heartbeat>,
+    /// How messages are framed, both ways. The default is what `quad_socket::server`
+    /// speaks, with the same `compress_above` as `Server::compress_above`. The
+    /// others are for servers with framing of their own.
+    pub frame_format: FrameFormat,
+    /// Hand messages to a writer thread instead of writing them in `send`, so a
+    /// connection that can't keep up never blocks the game loop, `false` by default.
+    /// `TcpSocket::send_buffer_len` tells how much is waiting.
+    pub nonblocking_send: bool,
+    /// Keep every sent message until the server acks it, and send the ones it may
+    /// have missed again after a `reconnect`, `false` by default.
+    ///
+    /// The server has to ack them, `quad_socket::server` does, and forgets sessions
+    /// that sent nothing for 10 minutes. Needs the default `frame_format`, acks are
+    /// control frames.
+    pub reliable: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            read_buffer_size: None,
+            write_buffer_size: None,
+            connect_timeout: None,
+            reconnect: None,
+            receive_capacity: None,
+            when_full: WhenFull::Pause,
+            max_message_size: MAX_MESSAGE_SIZE,
+            heartbeat: None,
+            frame_format: FrameFormat::default(),
+            nonblocking_send: false,
+            reliable: false,
+        }
+    }
+}
 
+/// Traffic of a `TcpSocket` so far, counting message frames with their length
+/// prefix. Heartbeats are left out, compressed messages count with their
+/// uncompressed size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+/// Behind `SocketStats`, shared by both halves and the reader thread.
+#[derive(Default)]
+struct Counters {
+    /// Length of the prefix in front of every message.
+    prefix_len: u64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+impl Counters {
+    fn sent(&self, data: &[u8]) {
+        self.bytes_sent
+            .fetch_add(self.prefix_len + data.len() as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn received(&self, data: &[u8]) {
+        self.bytes_received
+            .fetch_add(self.prefix_len + data.len() as u64, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> SocketStats {
+        SocketStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The native transport of `QuadSocket`, with everything only TCP can do.
 pub struct TcpSocket {
+    writer: TcpWriter,
+    reader: TcpReader,
+}
+
+/// Sending half of a `TcpSocket`, see `TcpSocket::split`.
+pub struct TcpWriter {
+    stream: TcpStream,
+    link: Arc<Mutex<Link>>,
+    /// Held while writing, so heartbeats never end up in the middle of a message.
+    writing: Arc<Mutex<()>>,
+    reconnect: Option<Reconnect>,
+    /// The writer thread, with `SocketOptions::nonblocking_send`.
+    outbound: Option<Outbound>,
+    /// With `SocketOptions::reliable`, also updated by the reader thread.
+    session: Option<Arc<Mutex<Session>>>,
+    counters: Arc<Counters>,
+    format: FrameFormat,
+}
+
+/// Receiving half of a `TcpSocket`, see `TcpSocket::split`.
+pub struct TcpReader {
+    /// Shared with the reader thread for `WhenFull::DropOldest`.
+    rx: Arc<Mutex<Receiver<Vec<u8>>>>,
+    /// Messages waiting in `rx`.
+    pending: Arc<AtomicUsize>,
+    /// Buffers given back by `try_recv_into` for the reader thread to fill again.
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    link: Arc<Mutex<Link>>,
+    counters: Arc<Counters>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+/// Shared by both halves and the reader thread.
+struct Link {
+    /// Updated by the reader thread.
+    status: ConnectionStatus,
+    /// Clone of the stream in use, shutting it down stops both halves.
     stream: TcpStream,
-    rx: Receiver<Vec<u8>>,
+    /// Measured with the heartbeat, see `TcpReader::last_rtt`.
+    last_rtt: Option<Duration>,
+    average_rtt: Option<Duration>,
+}
+
+/// Main thread side of the writer thread.
+struct Outbound {
+    tx: Sender<Outgoing>,
+    /// Bytes sent to the writer thread and not written yet.
+    len: Arc<AtomicUsize>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+enum Outgoing {
+    Frames(Vec<u8>),
+    /// Write to this stream from now on, after reconnecting.
+    Stream(TcpStream),
+}
+
+/// A `TcpSocket` still connecting, see `TcpSocket::connect_nonblocking`.
+pub struct PendingConnection {
+    rx: Receiver<Result<TcpSocket, Error>>,
+    done: bool,
+}
+
+impl PendingConnection {
+    /// The socket or why connecting failed, once it is known. `None` before,
+    /// and again after it was returned once.
+    pub fn poll(&mut self) -> Option<Result<TcpSocket, Error>> {
+        use std::sync::mpsc::TryRecvError;
+
+        if self.done {
+            return None;
+        }
+        let connected = match self.rx.try_recv() {
+            Ok(connected) => connected,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => {
+                Err(std::io::Error::other("connecting thread panicked").into())
+            }
+        };
+        self.done = true;
+
+        Some(connected)
+    }
+}
+
+/// Sent messages of a reliable session the server did not ack yet.
+struct Session {
+    id: u64,
+    /// Number of the next message sent.
+    next_seq: u64,
+    /// The messages numbered right below `next_seq`.
+    unacked: VecDeque<Vec<u8>>,
+}
+
+impl Session {
+    fn new() -> Session {
+        use std::hash::{BuildHasher, Hasher};
+
+        // Random, the server tells sessions apart by it
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos()),
+        );
+
+        Session {
+            id: hasher.finish(),
+            next_seq: 0,
+            unacked: VecDeque::new(),
+        }
+    }
+
+    fn first_unacked(&self) -> u64 {
+        self.next_seq - self.unacked.len() as u64
+    }
+
+    fn sent(&mut self, message: &[u8]) {
+        self.unacked.push_back(message.to_vec());
+        self.next_seq += 1;
+    }
+
+    /// Forget the messages numbered below `seq`.
+    fn ack(&mut self, seq: u64) {
+        let acked = seq
+            .saturating_sub(self.first_unacked())
+            .min(self.unacked.len() as u64);
+        self.unacked.drain(..acked as usize);
+    }
+}
+
+/// Main thread side of reconnecting.
+struct Reconnect {
+    /// Streams the reader thread dialed after the connection was lost.
+    streams: Receiver<TcpStream>,
+    /// Messages sent while reconnecting, `None` if they are refused instead.
+    queue: Option<Vec<Vec<u8>>>,
+}
+
+impl TcpSocket {
+    /// Fails while not connected, unless reconnecting with `queue_while_reconnecting`.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.writer.send(data)
+    }
+
+    pub fn send_str(&mut self, data: &str) -> Result<(), Error> {
+        self.writer.send_str(data)
+    }
+
+    /// Send all `messages` with a single write, so no other write ends up between them.
+    ///
+    /// Nothing is sent if one of them is too large.
+    pub fn send_batch(&mut self, messages: &[&[u8]]) -> Result<(), Error> {
+        self.writer.send_batch(messages)
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.reader.try_recv()
+    }
+
+    /// Like `try_recv`, but the message ends up in `buffer`, `false` if there was none.
+    ///
+    /// The old allocation of `buffer` is reused for a later message, so a loop
+    /// that keeps passing the same buffer stops allocating once warmed up.
+    pub fn try_recv_into(&mut self, buffer: &mut Vec<u8>) -> bool {
+        self.reader.try_recv_into(buffer)
+    }
+
+    /// Like `try_recv`, for messages that should be UTF-8 strings.
+    pub fn try_recv_str(&mut self) -> Option<Result<String, Error>> {
+        self.reader.try_recv_str()
+    }
+
+    /// Every message received so far, never blocks.
+    pub fn try_recv_all(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.reader.try_recv_all()
+    }
+
+    /// Wait up to `timeout` for a message, for handshakes before the game loop starts.
+    ///
+    /// Returns `None` right away once disconnected and all messages were received.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.reader.recv_timeout(timeout)
+    }
+
+    /// How many received messages wait for `try_recv`, to notice falling behind.
+    ///
+    /// With `WhenFull::Pause` this includes the message waiting for room.
+    pub fn pending_len(&self) -> usize {
+        self.reader.pending_len()
+    }
+
+    /// `false` once the server closed the connection or it broke,
+    /// and while reconnecting.
+    ///
+    /// Messages that arrived before are still received by `try_recv`.
+    pub fn is_connected(&self) -> bool {
+        self.writer.is_connected()
+    }
+
+    /// Address of the server, the one that was picked if the host resolved to several.
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.writer.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.writer.local_addr()
+    }
+
+    /// For showing "reconnecting..." and the like.
+    pub fn status(&self) -> ConnectionStatus {
+        self.writer.status()
+    }
+
+    /// Time between the last ping of the heartbeat and its pong, `None` without a
+    /// heartbeat or before the first pong.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.reader.last_rtt()
+    }
+
+    /// Like `last_rtt`, smoothed over the last pings for a steadier ping display.
+    pub fn average_rtt(&self) -> Option<Duration> {
+        self.reader.average_rtt()
+    }
+
+    /// Bytes and messages sent and received so far, for showing or capping bandwidth.
+    ///
+    /// Messages queued while reconnecting count once they are sent.
+    pub fn stats(&self) -> SocketStats {
+        self.writer.stats()
+    }
+
+    /// Bytes of sent messages the writer thread did not write yet, always `0`
+    /// without `SocketOptions::nonblocking_send`.
+    ///
+    /// Growing means the connection can't keep up, time to send less.
+    pub fn send_buffer_len(&self) -> usize {
+        self.writer.send_buffer_len()
+    }
+
+    /// Shut the connection down and wait for the reader thread to finish.
+    ///
+    /// Messages that already arrived are dropped along with the socket. If the socket
+    /// is in the middle of reconnecting this waits for the current attempt. Messages
+    /// waiting for the writer thread are written first, see `TcpWriter::close`.
+    pub fn close(self) {
+        let TcpSocket { writer, reader } = self;
+        writer.close();
+        reader.close();
+    }
+
+    /// Separate the halves, to send and receive from different threads.
+    ///
+    /// Closing or dropping either half shuts the connection down, the other
+    /// one then reports `ConnectionStatus::Disconnected`.
+    pub fn split(self) -> (TcpWriter, TcpReader) {
+        (self.writer, self.reader)
+    }
 }
 
+/// Typed messages, sent as JSON.
+#[cfg(feature = "serde")]
 impl TcpSocket {
-    pub fn send(&mut self, data: &[u8]) {
-        use std::io::Write;
+    pub fn send_msg<T: serde::Serialize>(&mut self, message: &T) -> Result<(), Error> {
+        self.send(&serde_json::to_vec(message)?)
+    }
+
+    /// Like `try_recv`, fails with `Error::JsonError` for a message that is not a `T`.
+    pub fn try_recv_msg<T: serde::de::DeserializeOwned>(&mut self) -> Option<Result<T, Error>> {
+        let message = self.try_recv()?;
+
+        Some(serde_json::from_slice(&message).map_err(Error::from))
+    }
+}
+
+impl TcpWriter {
+    /// Fails while not connected, unless reconnecting with `queue_while_reconnecting`.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > self.format.max_message_size() {
+            return Err(Error::MessageTooLarge);
+        }
+
+        match self.queue()? {
+            None => {
+                let mut frame = Vec::with_capacity(self.format.prefix_len() + data.len());
+                self.format.push_message(&mut frame, data)?;
+                self.write(frame)?;
+                self.counters.sent(data);
+                self.track(&[data]);
+                Ok(())
+            }
+            Some(queue) => {
+                queue.push(data.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    /// Send all `messages` with a single write, so no other write ends up between them.
+    ///
+    /// Nothing is sent if one of them is too large.
+    pub fn send_batch(&mut self, messages: &[&[u8]]) -> Result<(), Error> {
+        let max = self.format.max_message_size();
+        if messages.iter().any(|data| data.len() > max) {
+            return Err(Error::MessageTooLarge);
+        }
+
+        match self.queue()? {
+            None => {
+                let prefix_len = self.format.prefix_len();
+                let len = messages.iter().map(|data| prefix_len + data.len()).sum();
+                let mut frames = Vec::with_capacity(len);
+                for data in messages {
+                    self.format.push_message(&mut frames, data)?;
+                }
+
+                self.write(frames)?;
+                for data in messages {
+                    self.counters.sent(data);
+                }
+                self.track(messages);
+                Ok(())
+            }
+            Some(queue) => {
+                queue.extend(messages.iter().map(|data| data.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send_str(&mut self, data: &str) -> Result<(), Error> {
+        self.send(data.as_bytes())
+    }
 
-        self.stream.write(&[data.len() as u8]).unwrap();
-        self.stream.write(data).unwrap();
+    pub fn is_connected(&self) -> bool {
+        self.status() == ConnectionStatus::Connected
     }
 
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.link.lock().unwrap().status
+    }
+
+    /// See `TcpSocket::get_ref`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Traffic of both halves.
+    pub fn stats(&self) -> SocketStats {
+        self.counters.stats()
+    }
+
+    /// See `TcpSocket::send_buffer_len`.
+    pub fn send_buffer_len(&self) -> usize {
+        self.outbound
+            .as_ref()
+            .map_or(0, |outbound| outbound.len.load(Ordering::Relaxed))
+    }
+
+    /// Shut the connection down, the reader half then stops receiving.
+    ///
+    /// Waits for the writer thread to write what is waiting first, dropping the
+    /// writer shuts down right away instead.
+    pub fn close(mut self) {
+        if let Some(mut outbound) = self.outbound.take() {
+            drop(outbound.tx);
+            if let Some(thread) = outbound.thread.take() {
+                let _ = thread.join();
+            }
+        }
+
+        shutdown(&self.link);
+    }
+
+    /// Write `frames` to the stream, or hand them to the writer thread.
+    fn write(&mut self, frames: Vec<u8>) -> Result<(), Error> {
+        match &self.outbound {
+            Some(outbound) => {
+                outbound.len.fetch_add(frames.len(), Ordering::Relaxed);
+                // Only gone once the writer is
+                let _ = outbound.tx.send(Outgoing::Frames(frames));
+            }
+            None => {
+                let _writing = self.writing.lock().unwrap();
+                self.stream.write_all(&frames)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where messages go right now: `None` for the stream, or the queue while
+    /// reconnecting. Fails while not connected otherwise.
+    fn queue(&mut self) -> Result<Option<&mut Vec<Vec<u8>>>, Error> {
+        // Read first: a stream handed over before the reader thread reported
+        // `Connected` is then picked up right below
+        let status = self.status();
+        self.take_reconnected()?;
+
+        match (status, &mut self.reconnect) {
+            (ConnectionStatus::Connected, _) => Ok(None),
+            (
+                ConnectionStatus::Reconnecting,
+                Some(Reconnect {
+                    queue: Some(queue), ..
+                }),
+            ) => Ok(Some(queue)),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into()),
+        }
+    }
+
+    /// Keep messages that were just written for the reliable session.
+    fn track(&self, messages: &[&[u8]]) {
+        if let Some(session) = &self.session {
+            let mut session = session.lock().unwrap();
+            for message in messages {
+                session.sent(message);
+            }
+        }
+    }
+
+    /// Switch over to the latest stream from the reader thread, if there is one,
+    /// and send what was queued in the meantime.
+    ///
+    /// In a reliable session the reader thread already sent the messages the
+    /// server did not ack, the queued ones follow them.
+    fn take_reconnected(&mut self) -> Result<(), Error> {
+        let reconnect = match &mut self.reconnect {
+            Some(reconnect) => reconnect,
+            None => return Ok(()),
+        };
+        // Older streams were already shut down by the reader thread
+        let stream = match reconnect.streams.try_iter().last() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let queued = reconnect.queue.as_mut().map_or(vec![], std::mem::take);
+
+        if let Some(outbound) = &self.outbound {
+            let _ = outbound.tx.send(Outgoing::Stream(stream.try_clone()?));
+        }
+        self.stream = stream;
+
+        let mut frames = vec![];
+        for message in &queued {
+            self.format.push_message(&mut frames, message)?;
+            self.counters.sent(message);
+        }
+
+        if !frames.is_empty() {
+            self.write(frames)?;
+        }
+        self.track(&queued.iter().map(Vec::as_slice).collect::<Vec<_>>());
+
+        Ok(())
+    }
+}
+
+impl TcpReader {
     pub fn try_recv(&mut self) -> Option<Vec<u8>> {
-        self.rx.try_recv().ok()
+        let message = self.rx.lock().unwrap().try_recv().ok()?;
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+
+        Some(message)
+    }
+
+    /// See `TcpSocket::try_recv_into`.
+    pub fn try_recv_into(&mut self, buffer: &mut Vec<u8>) -> bool {
+        let mut message = match self.try_recv() {
+            Some(message) => message,
+            None => return false,
+        };
+        std::mem::swap(buffer, &mut message);
+
+        let mut free = self.free.lock().unwrap();
+        if free.len() < FREE_BUFFERS && message.capacity() > 0 {
+            free.push(message);
+        }
+
+        true
+    }
+
+    pub fn try_recv_str(&mut self) -> Option<Result<String, Error>> {
+        let message = self.try_recv()?;
+
+        Some(String::from_utf8(message).map_err(|_| Error::NotStrError))
+    }
+
+    pub fn try_recv_all(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        std::iter::from_fn(move || self.try_recv())
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        let message = self.rx.lock().unwrap().recv_timeout(timeout).ok()?;
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+
+        Some(message)
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status() == ConnectionStatus::Connected
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.link.lock().unwrap().status
+    }
+
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.link.lock().unwrap().last_rtt
+    }
+
+    /// Traffic of both halves.
+    pub fn stats(&self) -> SocketStats {
+        self.counters.stats()
+    }
+
+    pub fn average_rtt(&self) -> Option<Duration> {
+        self.link.lock().unwrap().average_rtt
+    }
+
+    /// Shut the connection down and wait for the reader thread to finish,
+    /// the writer half then fails to send.
+    pub fn close(mut self) {
+        shutdown(&self.link);
+        // Unblocks a reader thread waiting for room with `WhenFull::Pause`
+        self.rx = Arc::new(Mutex::new(mpsc::channel().1));
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
 }
 
+/// Shuts the connection down, the reader thread notices and exits on its own.
+impl Drop for TcpWriter {
+    fn drop(&mut self) {
+        shutdown(&self.link);
+    }
+}
+
+impl Drop for TcpReader {
+    fn drop(&mut self) {
+        shutdown(&self.link);
+    }
+}
+
+/// Make the reader thread stop for good.
+fn shutdown(link: &Mutex<Link>) {
+    // Held while shutting down, so the reader thread can't hand over
+    // a stream that is never shut down
+    let mut link = link.lock().unwrap();
+    link.status = ConnectionStatus::Disconnected;
+
+    let _ = link.stream.shutdown(std::net::Shutdown::Both);
+}
+
 impl TcpSocket {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpSocket, Error> {
-        let stream = TcpStream::connect(addr)?;
-        stream.set_nodelay(true).unwrap();
+        TcpSocket::connect_with_options(addr, SocketOptions::default())
+    }
 
+    /// Like `connect`, but dial the same address again whenever the connection is lost.
+    ///
+    /// Messages the server sent while the connection was going down are lost.
+    pub fn connect_with_reconnect<A: ToSocketAddrs>(
+        addr: A,
+        policy: ReconnectPolicy,
+    ) -> Result<TcpSocket, Error> {
+        TcpSocket::connect_with_options(
+            addr,
+            SocketOptions {
+                reconnect: Some(policy),
+                ..SocketOptions::default()
+            },
+        )
+    }
+
+    /// Like `connect`, fails with an `ErrorKind::TimedOut` IO error if no address
+    /// answered in time.
+    pub fn connect_timeout<A: ToSocketAddrs>(
+        addr: A,
+        timeout: Duration,
+    ) -> Result<TcpSocket, Error> {
+        TcpSocket::connect_with_options(
+            addr,
+            SocketOptions {
+                connect_timeout: Some(timeout),
+                ..SocketOptions::default()
+            },
+        )
+    }
+
+    /// Like `connect`, but resolve and dial `addr` on another thread, poll the
+    /// `PendingConnection` for the socket.
+    pub fn connect_nonblocking<A: ToSocketAddrs + Send + 'static>(addr: A) -> PendingConnection {
+        TcpSocket::connect_nonblocking_with_options(addr, SocketOptions::default())
+    }
+
+    /// `connect_nonblocking` with `connect_with_options`.
+    pub fn connect_nonblocking_with_options<A: ToSocketAddrs + Send + 'static>(
+        addr: A,
+        options: SocketOptions,
+    ) -> PendingConnection {
         let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(TcpSocket::connect_with_options(addr, options));
+        });
+
+        PendingConnection { rx, done: false }
+    }
+
+    /// Fails with an `ErrorKind::InvalidInput` IO error for a `heartbeat`,
+    /// `compress_above` or `reliable` with a `frame_format` that has no pings.
+    pub fn connect_with_options<A: ToSocketAddrs>(
+        addr: A,
+        options: SocketOptions,
+    ) -> Result<TcpSocket, Error> {
+        if options.heartbeat.is_some() && !options.frame_format.has_control_frames() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "heartbeats need the default frame format",
+            )
+            .into());
+        }
+        if options.reliable && !options.frame_format.has_control_frames() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reliable sessions need the default frame format",
+            )
+            .into());
+        }
+        if options.frame_format.compress_above.is_some()
+            && !options.frame_format.has_control_frames()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "compression needs the default frame format",
+            )
+            .into());
+        }
+
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let stream = dial(&addrs, &options)?;
+
+        TcpSocket::start(stream, addrs, options)
+    }
+
+    /// Take over a connection established elsewhere, as it is: none of the
+    /// `SocketOptions` are applied to it.
+    ///
+    /// Nothing else should read from `stream` afterwards, that would take bytes
+    /// away from the reader thread and break the framing.
+    pub fn from_stream(stream: TcpStream) -> Result<TcpSocket, Error> {
+        let addrs = vec![stream.peer_addr()?];
+
+        TcpSocket::start(stream, addrs, SocketOptions::default())
+    }
+
+    /// The stream in use, for socket options `SocketOptions` does not cover.
+    ///
+    /// Reading from it or writing to it directly breaks the framing. While
+    /// reconnecting it may still be the lost connection.
+    pub fn get_ref(&self) -> &TcpStream {
+        self.writer.get_ref()
+    }
+
+    /// Spawn the reader thread for `stream`, `addrs` are dialed again when reconnecting.
+    fn start(
+        stream: TcpStream,
+        addrs: Vec<SocketAddr>,
+        mut options: SocketOptions,
+    ) -> Result<TcpSocket, Error> {
+        // Blocking reads are what the reader thread expects
+        stream.set_nonblocking(false)?;
+        let reader = stream.try_clone()?;
+        let link = Arc::new(Mutex::new(Link {
+            status: ConnectionStatus::Connected,
+            stream: stream.try_clone()?,
+            last_rtt: None,
+            average_rtt: None,
+        }));
+
+        let pending = Arc::new(AtomicUsize::new(0));
+        let free = Arc::new(Mutex::new(vec![]));
+        let counters = Arc::new(Counters {
+            prefix_len: options.frame_format.prefix_len() as u64,
+            ..Counters::default()
+        });
+        let (inbox, rx) = Inbox::new(&options, pending.clone(), free.clone(), counters.clone());
+
+        let heartbeat = options.heartbeat.clone();
+        let format = options.frame_format;
+        let writing = Arc::new(Mutex::new(()));
+        let session = if options.reliable {
+            let session = Session::new();
+            write_resume(&stream, session.id, 0)?;
+            Some(Arc::new(Mutex::new(session)))
+        } else {
+            None
+        };
+        let outbound = if options.nonblocking_send {
+            let (tx, frames) = mpsc::channel();
+            let len = Arc::new(AtomicUsize::new(0));
+            let thread = std::thread::spawn({
+                let stream = stream.try_clone()?;
+                let writing = writing.clone();
+                let len = len.clone();
+                move || write_frames(stream, frames, writing, len)
+            });
+
+            Some(Outbound {
+                tx,
+                len,
+                thread: Some(thread),
+            })
+        } else {
+            None
+        };
+        let (reconnect, redial) = match options.reconnect.take() {
+            Some(policy) => {
+                let (streams_tx, streams) = mpsc::channel();
+                let reconnect = Reconnect {
+                    streams,
+                    queue: policy.queue_while_reconnecting.then(Vec::new),
+                };
+
+                let redial = Redial {
+                    addrs,
+                    options,
+                    policy,
+                    streams: streams_tx,
+                };
+
+                (Some(reconnect), Some(redial))
+            }
+            None => (None, None),
+        };
+
+        let thread = std::thread::spawn({
+            let link = link.clone();
+            let writing = writing.clone();
+            let session = session.clone();
+            move || read_messages(reader, inbox, link, writing, session, redial, heartbeat)
+        });
+
+        Ok(TcpSocket {
+            writer: TcpWriter {
+                stream,
+                link: link.clone(),
+                writing,
+                reconnect,
+                outbound,
+                session,
+                counters: counters.clone(),
+                format,
+            },
+            reader: TcpReader {
+                rx,
+                pending,
+                free,
+                link,
+                counters,
+                thread: Some(thread),
+            },
+        })
+    }
+}
+
+/// Reader thread side of the received messages.
+struct Inbox {
+    tx: InboxSender,
+    /// Same counter as `TcpSocket::pending`.
+    pending: Arc<AtomicUsize>,
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    counters: Arc<Counters>,
+    max_message_size: usize,
+    format: FrameFormat,
+}
+
+enum InboxSender {
+    Unbounded(Sender<Vec<u8>>),
+    Bounded(SyncSender<Vec<u8>>, WhenFull),
+    /// `WhenFull::DropOldest` takes messages out of the channel itself.
+    DropOldest(SyncSender<Vec<u8>>, Arc<Mutex<Receiver<Vec<u8>>>>),
+}
+
+impl Inbox {
+    fn new(
+        options: &SocketOptions,
+        pending: Arc<AtomicUsize>,
+        free: Arc<Mutex<Vec<Vec<u8>>>>,
+        counters: Arc<Counters>,
+    ) -> (Inbox, Arc<Mutex<Receiver<Vec<u8>>>>) {
+        let (tx, rx) = match options.receive_capacity {
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (InboxSender::Unbounded(tx), Arc::new(Mutex::new(rx)))
+            }
+            Some(capacity) => {
+                // Zero would be a rendezvous channel, nothing could ever wait
+                let (tx, rx) = mpsc::sync_channel(capacity.max(1));
+                let rx = Arc::new(Mutex::new(rx));
+                let tx = match options.when_full {
+                    WhenFull::DropOldest => InboxSender::DropOldest(tx, rx.clone()),
+                    when_full => InboxSender::Bounded(tx, when_full),
+                };
+                (tx, rx)
+            }
+        };
+
+        let inbox = Inbox {
+            tx,
+            pending,
+            free,
+            counters,
+            max_message_size: options.max_message_size,
+            format: options.frame_format,
+        };
+
+        (inbox, rx)
+    }
+
+    /// Hand `message` over to the socket, `false` once the socket is gone.
+    fn push(&self, mut message: Vec<u8>) -> bool {
+        // Counted first, so taking it out on the other side never goes below zero
+        self.pending.fetch_add(1, Ordering::Relaxed);
+
+        let delivered = match &self.tx {
+            InboxSender::Unbounded(tx) => tx.send(message).map_err(|_| ()),
+            InboxSender::Bounded(tx, WhenFull::Pause) => tx.send(message).map_err(|_| ()),
+            InboxSender::Bounded(tx, _) => match tx.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.pending.fetch_sub(1, Ordering::Relaxed);
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => Err(()),
+            },
+            InboxSender::DropOldest(tx, rx) => loop {
+                match tx.try_send(message) {
+                    Ok(()) => break Ok(()),
+                    Err(TrySendError::Full(returned)) => {
+                        message = returned;
+                        if rx.lock().unwrap().try_recv().is_ok() {
+                            self.pending.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(TrySendError::Disconnected(_)) => break Err(()),
+                }
+            },
+        };
+
+        delivered.is_ok()
+    }
+}
+
+/// Everything the reader thread needs to dial again.
+struct Redial {
+    addrs: Vec<SocketAddr>,
+    options: SocketOptions,
+    policy: ReconnectPolicy,
+    /// Hands the new streams to the `TcpWriter`.
+    streams: Sender<TcpStream>,
+}
+
+/// Reader thread: forward messages from `stream` to `inbox` until the connection is
+/// lost for good, or the socket is gone or closed.
+///
+/// The status being `Disconnected` while the thread is still running means the socket
+/// was closed, it is checked with the lock held before anything is handed over.
+fn read_messages(
+    mut stream: TcpStream,
+    inbox: Inbox,
+    link: Arc<Mutex<Link>>,
+    writing: Arc<Mutex<()>>,
+    session: Option<Arc<Mutex<Session>>>,
+    redial: Option<Redial>,
+    heartbeat: Option<Heartbeat>,
+) {
+    loop {
+        let mut messages =
+            MessageReader::with_max_message_size(inbox.max_message_size).with_format(inbox.format);
+        let mut last_received = Instant::now();
+        let mut last_ping = Instant::now();
+        // Sequence number of the last ping, waiting for its pong
+        let mut ping = 0u32;
+        if let Some(heartbeat) = &heartbeat {
+            let _ = stream.set_read_timeout(Some(heartbeat.interval.min(heartbeat.timeout)));
+        }
+
+        // The stream is blocking, so without a heartbeat this only loops when there
+        // is something to read, and stops for good once the stream or the socket is gone
+        loop {
+            match messages.next_frame(&mut stream) {
+                Ok(Some(frame)) => {
+                    last_received = Instant::now();
+                    match frame {
+                        Frame::Message(message) => {
+                            inbox.counters.received(&message);
+                            if !inbox.push(message) {
+                                return;
+                            }
+                            if let Some(buffer) = inbox.free.lock().unwrap().pop() {
+                                messages.reuse(buffer);
+                            }
+                        }
+                        Frame::Ping(payload) => {
+                            let _writing = writing.lock().unwrap();
+                            let _ = write_pong(&stream, &payload);
+                        }
+                        Frame::Pong(payload) if payload == ping.to_be_bytes() => {
+                            let rtt = last_ping.elapsed();
+                            let mut link = link.lock().unwrap();
+                            link.last_rtt = Some(rtt);
+                            // Smoothed like TCP does it
+                            link.average_rtt = Some(
+                                link.average_rtt
+                                    .map_or(rtt, |average| (average * 7 + rtt) / 8),
+                            );
+                        }
+                        Frame::Ack(seq) => {
+                            if let Some(session) = &session {
+                                session.lock().unwrap().ack(seq);
+                            }
+                        }
+                        // Only the server is told about sessions
+                        Frame::Pong(_) | Frame::Resume { .. } => {}
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => break,
+            }
+
+            if let Some(heartbeat) = &heartbeat {
+                if last_received.elapsed() >= heartbeat.timeout {
+                    break;
+                }
+                if last_ping.elapsed() >= heartbeat.interval {
+                    last_ping = Instant::now();
+                    ping = ping.wrapping_add(1);
+                    let _writing = writing.lock().unwrap();
+                    let _ = write_ping(&stream, &ping.to_be_bytes());
+                }
+            }
+        }
+        // After a frame that was too large or a heartbeat timeout the connection is still open
+        let _ = stream.shutdown(std::net::Shutdown::Both);
 
-        std::thread::spawn({
-            let mut stream = stream.try_clone().unwrap();
-            move || {
-                let mut messages = MessageReader::new();
-                loop {
-                    if let Ok(Some(message)) = messages.next(&mut stream) {
-                        tx.send(message).unwrap();
+        let redial = match &redial {
+            Some(redial) => redial,
+            None => {
+                link.lock().unwrap().status = ConnectionStatus::Disconnected;
+                return;
+            }
+        };
+
+        {
+            let mut link = link.lock().unwrap();
+            if link.status == ConnectionStatus::Disconnected {
+                return;
+            }
+            link.status = ConnectionStatus::Reconnecting;
+        }
+
+        let new_stream = dial_again(redial, &link);
+        let clones = new_stream
+            .as_ref()
+            .and_then(|stream| Some((stream.try_clone().ok()?, stream.try_clone().ok()?)));
+
+        match (new_stream, clones) {
+            (Some(new_stream), Some((writer, shared))) => {
+                {
+                    let mut link = link.lock().unwrap();
+                    if link.status == ConnectionStatus::Disconnected {
+                        return;
                     }
+                    // Shut down with the socket, should that happen while resuming
+                    link.stream = shared;
+                }
+                // Right away, not with the next `send`: a socket may only ever receive.
+                // Failing means the new connection is lost too, reading notices
+                if let Some(session) = &session {
+                    let _ = resume(&new_stream, session, inbox.format, &writing);
                 }
+
+                let mut link = link.lock().unwrap();
+                if link.status == ConnectionStatus::Disconnected
+                    || redial.streams.send(writer).is_err()
+                {
+                    return;
+                }
+
+                stream = new_stream;
+                link.status = ConnectionStatus::Connected;
             }
+            _ => {
+                link.lock().unwrap().status = ConnectionStatus::Disconnected;
+                return;
+            }
+        }
+    }
+}
+
+/// Tell the server on the other end of `stream` which session it is, and send the
+/// messages it did not ack again, it skips those it already got.
+fn resume(
+    mut stream: &TcpStream,
+    session: &Mutex<Session>,
+    format: FrameFormat,
+    writing: &Mutex<()>,
+) -> Result<(), Error> {
+    let mut frames = vec![];
+    {
+        let session = session.lock().unwrap();
+        write_resume(&mut frames, session.id, session.first_unacked())?;
+        for message in &session.unacked {
+            format.push_message(&mut frames, message)?;
+        }
+    }
+
+    let _writing = writing.lock().unwrap();
+    stream.write_all(&frames)?;
+
+    Ok(())
+}
+
+/// Writer thread: write what `TcpWriter` hands over until it is gone.
+///
+/// Frames for a lost connection are dropped, the same messages would have failed
+/// to send without the thread.
+fn write_frames(
+    mut stream: TcpStream,
+    frames: Receiver<Outgoing>,
+    writing: Arc<Mutex<()>>,
+    len: Arc<AtomicUsize>,
+) {
+    for outgoing in frames {
+        match outgoing {
+            Outgoing::Frames(frames) => {
+                {
+                    let _writing = writing.lock().unwrap();
+                    let _ = stream.write_all(&frames);
+                }
+                len.fetch_sub(frames.len(), Ordering::Relaxed);
+            }
+            Outgoing::Stream(new_stream) => stream = new_stream,
+        }
+    }
+}
+
+/// Try to connect as often as the policy allows, `None` once out of attempts
+/// or once the socket was closed.
+fn dial_again(redial: &Redial, link: &Mutex<Link>) -> Option<TcpStream> {
+    let policy = &redial.policy;
+    let mut delay = policy.backoff;
+    let mut attempts = 0;
+
+    while policy.max_attempts.is_none_or(|max| attempts < max) {
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(policy.max_backoff);
+        attempts += 1;
+
+        // Closed or dropped in the meantime
+        if link.lock().unwrap().status == ConnectionStatus::Disconnected {
+            return None;
+        }
+
+        if let Ok(stream) = dial(&redial.addrs, &redial.options) {
+            return Some(stream);
+        }
+    }
+
+    None
+}
+
+/// Connect to the first of `addrs` that answers and apply `options`.
+fn dial(addrs: &[SocketAddr], options: &SocketOptions) -> std::io::Result<TcpStream> {
+    let stream = connect_any(addrs, options.connect_timeout)?;
+
+    stream.set_nodelay(options.nodelay)?;
+
+    let socket = socket2::SockRef::from(&stream);
+    if let Some(size) = options.read_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.write_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    Ok(stream)
+}
+
+/// Connect to whichever of `addrs` answers first, trying them in turn but not waiting
+/// more than `ATTEMPT_DELAY` for one before starting the next (happy eyeballs).
+///
+/// IPv6 and IPv4 addresses take turns, so a host with a broken AAAA record still
+/// connects quickly. Fails with the last error, or `ErrorKind::TimedOut` once
+/// `timeout` is over.
+pub(super) fn connect_any(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+) -> std::io::Result<TcpStream> {
+    let connect = move |addr: &SocketAddr| match timeout {
+        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+        None => TcpStream::connect(addr),
+    };
+    if let [addr] = addrs {
+        return connect(addr);
+    }
+
+    // Starting with the family the resolver put first
+    let first_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (first, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_ipv6);
+    let mut ordered = vec![];
+    for i in 0..first.len().max(other.len()) {
+        ordered.extend(first.get(i).into_iter().chain(other.get(i)));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (tx, rx) = mpsc::channel();
+    let mut last_error = std::io::ErrorKind::InvalidInput.into();
+    let mut running = 0;
+
+    let mut next = ordered.into_iter();
+    loop {
+        if let Some(addr) = next.next() {
+            let tx = tx.clone();
+            // A stream connected after another one won is dropped with the failed send
+            std::thread::spawn(move || {
+                let _ = tx.send(connect(&addr));
+            });
+            running += 1;
+        } else if running == 0 {
+            return Err(last_error);
+        }
+
+        let wait = match (next.len(), deadline) {
+            (0, Some(deadline)) => deadline.saturating_duration_since(Instant::now()),
+            (0, None) => Duration::MAX,
+            (_, Some(deadline)) => {
+                ATTEMPT_DELAY.min(deadline.saturating_duration_since(Instant::now()))
+            }
+            (_, None) => ATTEMPT_DELAY,
+        };
+        match rx.recv_timeout(wait) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(error)) => {
+                last_error = error;
+                running -= 1;
+            }
+            Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbox(capacity: usize, when_full: WhenFull) -> (Inbox, Arc<Mutex<Receiver<Vec<u8>>>>) {
+        let options = SocketOptions {
+            receive_capacity: Some(capacity),
+            when_full,
+            ..SocketOptions::default()
+        };
+
+        Inbox::new(&options, Arc::default(), Arc::default(), Arc::default())
+    }
+
+    fn received(rx: &Mutex<Receiver<Vec<u8>>>) -> Vec<Vec<u8>> {
+        rx.lock().unwrap().try_iter().collect()
+    }
+
+    /// A socket connected with `options` to the returned stream.
+    fn connected(options: SocketOptions) -> (TcpSocket, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket =
+            TcpSocket::connect_with_options(listener.local_addr().unwrap(), options).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+
+        (socket, peer)
+    }
+
+    /// Whether everything holding on to `weak` let go of it within a second.
+    fn released<T>(weak: &std::sync::Weak<T>) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while weak.upgrade().is_some() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        for capacity in [0, 1] {
+            let (inbox, rx) = inbox(capacity, WhenFull::DropOldest);
+            assert!(inbox.push(b"first".to_vec()));
+            assert!(inbox.push(b"second".to_vec()));
+
+            assert_eq!(received(&rx), [b"second".to_vec()]);
+            assert_eq!(inbox.pending.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_oldest() {
+        for capacity in [0, 1] {
+            let (inbox, rx) = inbox(capacity, WhenFull::DropNewest);
+            assert!(inbox.push(b"first".to_vec()));
+            assert!(inbox.push(b"second".to_vec()));
+
+            assert_eq!(received(&rx), [b"first".to_vec()]);
+            assert_eq!(inbox.pending.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn pause_waits_for_room() {
+        for capacity in [0, 1] {
+            let (inbox, rx) = inbox(capacity, WhenFull::Pause);
+            assert!(inbox.push(b"first".to_vec()));
+
+            let pusher = std::thread::spawn(move || inbox.push(b"second".to_vec()));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!pusher.is_finished());
+
+            assert_eq!(rx.lock().unwrap().recv().unwrap(), b"first");
+            assert!(pusher.join().unwrap());
+            assert_eq!(received(&rx), [b"second".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn threads_exit_on_drop() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut threads = vec![];
+        for _ in 0..100 {
+            let socket = TcpSocket::connect_with_options(
+                listener.local_addr().unwrap(),
+                SocketOptions {
+                    nonblocking_send: true,
+                    ..SocketOptions::default()
+                },
+            )
+            .unwrap();
+            let (mut peer, _) = listener.accept().unwrap();
+            // Held by the reader thread, and with the writer thread by both of them
+            threads.push((
+                Arc::downgrade(&socket.writer.link),
+                Arc::downgrade(&socket.writer.writing),
+            ));
+            drop(socket);
+
+            let mut received = vec![];
+            assert_eq!(peer.read_to_end(&mut received).unwrap(), 0);
+        }
+
+        for (link, writing) in &threads {
+            assert!(released(link));
+            assert!(released(writing));
+        }
+    }
+
+    #[test]
+    fn heartbeat_stops_on_drop() {
+        let (socket, _peer) = connected(SocketOptions {
+            heartbeat: Some(Heartbeat {
+                interval: Duration::from_millis(10),
+                timeout: Duration::from_secs(60),
+            }),
+            ..SocketOptions::default()
         });
+        // Long before the timeout would stop it, the peer never answers
+        std::thread::sleep(Duration::from_millis(50));
+        let link = Arc::downgrade(&socket.writer.link);
+        drop(socket);
+
+        assert!(released(&link));
+    }
+
+    #[test]
+    fn unacked_messages_are_sent_again_without_sending() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpSocket::connect_with_options(
+            listener.local_addr().unwrap(),
+            SocketOptions {
+                reliable: true,
+                reconnect: Some(ReconnectPolicy {
+                    backoff: Duration::from_millis(10),
+                    ..ReconnectPolicy::default()
+                }),
+                ..SocketOptions::default()
+            },
+        )
+        .unwrap();
+        let (mut writer, _reader) = socket.split();
+        writer.send(b"a").unwrap();
+        writer.send(b"b").unwrap();
+
+        let frames = |count: usize| {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Fails instead of hanging if they never come
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut messages = MessageReader::new();
+            let frames = (0..count)
+                .map(|_| messages.next_frame(&mut stream).unwrap().unwrap())
+                .collect::<Vec<_>>();
+            (stream, frames)
+        };
+        // Never acked, then lost
+        let (lost, first) = frames(3);
+        drop(lost);
 
-        Ok(TcpSocket { stream, rx })
+        let (_stream, resumed) = frames(3);
+        let session = match first[0] {
+            Frame::Resume { session, seq: 0 } => session,
+            ref frame => panic!("expected a resume, got {:?}", frame),
+        };
+        assert_eq!(
+            resumed,
+            [
+                Frame::Resume { session, seq: 0 },
+                Frame::Message(b"a".to_vec()),
+                Frame::Message(b"b".to_vec()),
+            ]
+        );
+        assert_eq!(first[1..], resumed[1..]);
     }
 }
@@ -0,0 +1,357 @@
+use std::{
+    convert::TryFrom,
+    io::Read,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore,
+};
+
+use super::ConnectionStatus;
+use crate::{
+    error::Error,
+    quad_socket::protocol::{write_message, Frame, MessageReader, MAX_MESSAGE_SIZE},
+};
+
+/// How long the server has for the TLS handshake, before `connect` gives up on it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Like `TcpSocket`, but encrypted with TLS.
+///
+/// The framing is the same, so a server that speaks it behind TLS is all it takes.
+/// On web use `QuadSocket` with a `wss://` address instead.
+pub struct TlsSocket {
+    /// Also read by the reader thread, its TLS session is shared under `session`.
+    stream: TcpStream,
+    session: Arc<Mutex<ClientConnection>>,
+    rx: Receiver<Vec<u8>>,
+    /// Updated by the reader thread.
+    status: Arc<Mutex<ConnectionStatus>>,
+    reader: Option<std::thread::JoinHandle<()>>,
+}
+
+impl TlsSocket {
+    /// Connect to `addr` and verify it is `server_name` with the bundled root certificates.
+    ///
+    /// Fails with a `TimedOut` or `WouldBlock` IO error if the handshake did not
+    /// finish within 10 seconds.
+    pub fn connect<A: ToSocketAddrs>(addr: A, server_name: &str) -> Result<TlsSocket, Error> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        TlsSocket::connect_with_roots(addr, server_name, roots)
+    }
+
+    /// Like `connect`, but only trust the PEM encoded certificate `pem`,
+    /// for servers signed by a private CA or with a self-made certificate.
+    pub fn connect_with_root_certificate<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        pem: &[u8],
+    ) -> Result<TlsSocket, Error> {
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_slice_iter(pem) {
+            roots
+                .add(certificate.map_err(invalid_data)?)
+                .map_err(invalid_data)?;
+        }
+        if roots.is_empty() {
+            return Err(invalid_data("no certificate in PEM").into());
+        }
+
+        TlsSocket::connect_with_roots(addr, server_name, roots)
+    }
+
+    fn connect_with_roots<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        roots: RootCertStore,
+    ) -> Result<TlsSocket, Error> {
+        let mut session = client_session(server_name, roots)?;
+
+        // Messages are written out right after they were encrypted, no need to
+        // limit how much of them waits
+        session.set_buffer_limit(None);
+
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let mut stream = super::tcp::connect_any(&addrs, None)?;
+        stream.set_nodelay(true)?;
+        handshake(&mut session, &mut stream, HANDSHAKE_TIMEOUT)?;
+
+        let reader = stream.try_clone()?;
+        let session = Arc::new(Mutex::new(session));
+        let (tx, rx) = mpsc::channel();
+        let status = Arc::new(Mutex::new(ConnectionStatus::Connected));
+
+        let reader = std::thread::spawn({
+            let session = session.clone();
+            let status = status.clone();
+            move || read_messages(reader, session, tx, status)
+        });
+
+        Ok(TlsSocket {
+            stream,
+            session,
+            rx,
+            status,
+            reader: Some(reader),
+        })
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send_batch(&[data])
+    }
+
+    pub fn send_str(&mut self, data: &str) -> Result<(), Error> {
+        self.send(data.as_bytes())
+    }
+
+    /// Send all `messages` at once, so no other write ends up between them.
+    ///
+    /// Nothing is sent if one of them is too large.
+    pub fn send_batch(&mut self, messages: &[&[u8]]) -> Result<(), Error> {
+        if messages.iter().any(|data| data.len() > MAX_MESSAGE_SIZE) {
+            return Err(Error::MessageTooLarge);
+        }
+        if !self.is_connected() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+        }
+
+        let mut session = self.session.lock().unwrap();
+        for data in messages {
+            write_message(session.writer(), data)?;
+        }
+        while session.wants_write() {
+            session.write_tls(&mut self.stream)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Like `try_recv`, for messages that should be UTF-8 strings.
+    pub fn try_recv_str(&mut self) -> Option<Result<String, Error>> {
+        let message = self.try_recv()?;
+
+        Some(String::from_utf8(message).map_err(|_| Error::NotStrError))
+    }
+
+    /// Every message received so far, never blocks.
+    pub fn try_recv_all(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.rx.try_iter()
+    }
+
+    /// Wait up to `timeout` for a message, for handshakes before the game loop starts.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+
+    /// `false` once the server closed the connection or it broke.
+    pub fn is_connected(&self) -> bool {
+        self.status() == ConnectionStatus::Connected
+    }
+
+    /// `Connected` or `Disconnected`, a `TlsSocket` does not reconnect.
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.lock().unwrap()
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Tell the server the connection ends, then shut it down and wait for
+    /// the reader thread to finish.
+    pub fn close(mut self) {
+        self.shutdown();
+
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        *self.status.lock().unwrap() = ConnectionStatus::Disconnected;
+
+        let mut session = self.session.lock().unwrap();
+        session.send_close_notify();
+        while session.wants_write() {
+            if session.write_tls(&mut self.stream).is_err() {
+                break;
+            }
+        }
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Shuts the connection down, the reader thread notices and exits on its own.
+impl Drop for TlsSocket {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A session that only trusts `server_name` signed by `roots`.
+fn client_session(server_name: &str, roots: RootCertStore) -> std::io::Result<ClientConnection> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    ClientConnection::new(Arc::new(config), server_name).map_err(invalid_data)
+}
+
+/// Finish the handshake of `session` over `stream`, failing once the server kept
+/// quiet for `timeout`.
+fn handshake(
+    session: &mut ClientConnection,
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    while session.is_handshaking() {
+        session.complete_io(&mut *stream)?;
+    }
+
+    // The reader thread waits for as long as it takes
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)
+}
+
+/// Reader thread: decrypt what arrives on `stream` and forward the messages
+/// to `tx` until the connection or the socket is gone.
+fn read_messages(
+    mut stream: TcpStream,
+    session: Arc<Mutex<ClientConnection>>,
+    tx: Sender<Vec<u8>>,
+    status: Arc<Mutex<ConnectionStatus>>,
+) {
+    let mut messages = MessageReader::new();
+    let mut buffer = vec![0; 16 * 1024];
+
+    // The socket is read without the lock, so sending is never held up waiting for data
+    'read: while let Ok(len @ 1..) = stream.read(&mut buffer) {
+        let mut session = session.lock().unwrap();
+        let mut received = &buffer[..len];
+        while !received.is_empty() {
+            if session.read_tls(&mut received).is_err() || session.process_new_packets().is_err() {
+                break 'read;
+            }
+        }
+        // Alerts and the like
+        while session.wants_write() {
+            if session.write_tls(&mut stream).is_err() {
+                break 'read;
+            }
+        }
+
+        // Ends with `WouldBlock` once all decrypted bytes are read
+        loop {
+            match messages.next_frame(session.reader()) {
+                Ok(Some(Frame::Message(message))) => {
+                    if tx.send(message).is_err() {
+                        break 'read;
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => break 'read,
+            }
+        }
+    }
+
+    *status.lock().unwrap() = ConnectionStatus::Disconnected;
+}
+
+fn invalid_data<E: std::fmt::Display>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Write, net::TcpListener};
+
+    use crate::test_tls::{accept, CERTIFICATE};
+
+    /// Echoes every message over TLS, returns the address to connect to.
+    fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = accept(stream.unwrap());
+                let mut messages = MessageReader::new();
+                // Until the client goes away, or never trusted the certificate
+                while let Ok(Some(Frame::Message(message))) = messages.next_frame(&mut stream) {
+                    if write_message(&mut stream, &message).is_err() || stream.flush().is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut socket =
+            TlsSocket::connect_with_root_certificate(echo(), "localhost", CERTIFICATE).unwrap();
+
+        socket.send(b"hello").unwrap();
+        socket.send_batch(&[b"a", b"b"]).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(socket.recv_timeout(timeout).unwrap(), b"hello");
+        assert_eq!(socket.recv_timeout(timeout).unwrap(), b"a");
+        assert_eq!(socket.recv_timeout(timeout).unwrap(), b"b");
+        socket.close();
+    }
+
+    #[test]
+    fn untrusted_certificates_are_refused() {
+        assert!(TlsSocket::connect(echo(), "localhost").is_err());
+        // Trusted, but for another name
+        assert!(
+            TlsSocket::connect_with_root_certificate(echo(), "example.com", CERTIFICATE).is_err()
+        );
+    }
+
+    #[test]
+    fn handshake_gives_up_on_silent_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // Accepted, but never answered
+        let _accepted = listener.accept().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut session = client_session("localhost", roots).unwrap();
+
+        let start = std::time::Instant::now();
+        assert!(handshake(&mut session, &mut stream, Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
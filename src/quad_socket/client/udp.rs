@@ -0,0 +1,259 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::error::Error;
+
+/// How often the reader thread checks whether the socket was dropped.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How far behind the newest datagram one of `connect_ordered` may be to be dropped
+/// as late. Any further and the peer started counting over, after reconnecting.
+const LATE_WINDOW: u32 = 1024;
+
+/// Unreliable, unordered and fast: every datagram is one message, some never arrive.
+///
+/// Same shape as `TcpSocket`, for things like position updates that are
+/// outdated by the time a lost one would be sent again.
+/// `connect_ordered` drops late ones instead of receiving them out of order.
+pub struct UdpSocket {
+    socket: std::net::UdpSocket,
+    rx: Receiver<Vec<u8>>,
+    /// Tells the reader thread to stop.
+    closed: Arc<AtomicBool>,
+    /// Number of the next datagram sent, see `connect_ordered`.
+    next_sequence: Option<u32>,
+}
+
+impl UdpSocket {
+    /// Nothing goes over the network yet, this only picks the address
+    /// datagrams are sent to and received from.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<UdpSocket, Error> {
+        UdpSocket::open(addr, false)
+    }
+
+    /// Like `connect`, but every datagram is numbered and only ones newer than
+    /// everything received before are received, so they come in order and once.
+    ///
+    /// Late datagrams are dropped, not held back until the missing ones arrive,
+    /// which would never happen for lost ones. For state snapshots where only the
+    /// newest matters. A peer that counts over, like after reconnecting, is noticed
+    /// once it is far enough behind.
+    ///
+    /// Both sides have to number their datagrams, the peer has to use
+    /// `connect_ordered` too.
+    pub fn connect_ordered<A: ToSocketAddrs>(addr: A) -> Result<UdpSocket, Error> {
+        UdpSocket::open(addr, true)
+    }
+
+    fn open<A: ToSocketAddrs>(addr: A, ordered: bool) -> Result<UdpSocket, Error> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0, 0, 0, 0, 0, 0, 0, 0], 0).into(),
+        };
+
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+
+        let reader = socket.try_clone()?;
+        reader.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let (tx, rx) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        std::thread::spawn({
+            let closed = closed.clone();
+            move || {
+                // Largest possible datagram
+                let mut buffer = vec![0; 65536];
+                let mut newest = None;
+
+                while !closed.load(Ordering::Relaxed) {
+                    // Errors are timeouts, or like `ConnectionRefused` about an earlier
+                    // datagram that was not received, nothing to stop for
+                    let Ok(len) = reader.recv(&mut buffer) else {
+                        continue;
+                    };
+                    let data = if ordered {
+                        match in_order(&buffer[..len], &mut newest) {
+                            Some(data) => data,
+                            // Late or a duplicate
+                            None => continue,
+                        }
+                    } else {
+                        &buffer[..len]
+                    };
+                    if tx.send(data.to_vec()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(UdpSocket {
+            socket,
+            rx,
+            closed,
+            next_sequence: ordered.then_some(0),
+        })
+    }
+
+    /// Send `data` as one datagram, it should stay below about 1200 bytes
+    /// to get through every network unfragmented.
+    ///
+    /// With `connect_ordered` the number takes 4 more bytes.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        match &mut self.next_sequence {
+            Some(sequence) => {
+                let mut datagram = Vec::with_capacity(4 + data.len());
+                datagram.extend_from_slice(&sequence.to_be_bytes());
+                datagram.extend_from_slice(data);
+                self.socket.send(&datagram)?;
+
+                *sequence = sequence.wrapping_add(1);
+            }
+            None => {
+                self.socket.send(data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The data of a numbered `datagram`, `None` if it is not newer than `newest`.
+fn in_order<'a>(datagram: &'a [u8], newest: &mut Option<u32>) -> Option<&'a [u8]> {
+    // Too short to be numbered
+    let (sequence, data) = datagram.split_first_chunk::<4>()?;
+    let sequence = u32::from_be_bytes(*sequence);
+
+    if let Some(newest) = *newest {
+        // Wraps around, so the numbers never run out
+        let behind = newest.wrapping_sub(sequence);
+        if behind < LATE_WINDOW {
+            return None;
+        }
+    }
+    *newest = Some(sequence);
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    #[test]
+    fn round_trip_without_blocking() {
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut socket = UdpSocket::connect(peer.local_addr().unwrap()).unwrap();
+
+        // Nothing arrived yet, well below the reader thread's timeout
+        let start = Instant::now();
+        assert_eq!(socket.try_recv(), None);
+        assert!(start.elapsed() < READ_TIMEOUT / 2);
+
+        socket.send(b"ping").unwrap();
+        let mut buffer = [0; 16];
+        let (len, from) = peer.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+
+        peer.send_to(b"pong", from).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let received = loop {
+            if let Some(message) = socket.try_recv() {
+                break message;
+            }
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(received, b"pong");
+    }
+
+    #[test]
+    fn in_order_drops_late_datagrams_and_duplicates() {
+        let max = u32::MAX;
+        // Newest before, number of the datagram, whether it is received, newest after
+        let cases = [
+            (None, 5, true, Some(5)),
+            (Some(5), 6, true, Some(6)),
+            (Some(5), 9, true, Some(9)),
+            (Some(5), 5, false, Some(5)),
+            (Some(5), 4, false, Some(5)),
+            (Some(2000), 2000 - (LATE_WINDOW - 1), false, Some(2000)),
+            // Too far behind to be late, the peer counted over
+            (
+                Some(2000),
+                2000 - LATE_WINDOW,
+                true,
+                Some(2000 - LATE_WINDOW),
+            ),
+            (Some(5), max, false, Some(5)),
+            (
+                Some(5),
+                5u32.wrapping_sub(LATE_WINDOW),
+                true,
+                Some(5u32.wrapping_sub(LATE_WINDOW)),
+            ),
+            // Wrapping around
+            (Some(max), 0, true, Some(0)),
+            (Some(max - 1), 1, true, Some(1)),
+            (Some(0), max, false, Some(0)),
+            (Some(1), max - 1, false, Some(1)),
+        ];
+
+        for (before, sequence, received, after) in cases {
+            let mut datagram = sequence.to_be_bytes().to_vec();
+            datagram.push(7);
+            let mut newest = before;
+
+            let data = in_order(&datagram, &mut newest);
+            assert_eq!(data.is_some(), received, "{:?} then {}", before, sequence);
+            if received {
+                assert_eq!(data, Some(&[7][..]));
+            }
+            assert_eq!(newest, after, "{:?} then {}", before, sequence);
+        }
+    }
+
+    #[test]
+    fn in_order_ignores_datagrams_too_short_to_be_numbered() {
+        let mut newest = Some(5);
+        assert_eq!(in_order(&[0, 0, 0], &mut newest), None);
+        assert_eq!(newest, Some(5));
+
+        // A number without data is still a message
+        assert_eq!(in_order(&[0, 0, 0, 6], &mut newest), Some(&[][..]));
+        assert_eq!(newest, Some(6));
+    }
+}
@@ -1,37 +1,554 @@
-use std::io::ErrorKind;
+//! The framing `TcpSocket` and the server speak over TCP, for use over other
+//! transports too: every message is sent as a 4 byte big endian length, then
+//! the message itself.
+//!
+//! A length with the highest bit set marks a control frame instead, its first
+//! byte says what kind: pings are answered with a pong carrying the same bytes.
+//! The next bit marks a message compressed with deflate, see
+//! `FrameFormat::compress_above`.
+//!
+//! Reliable sessions number the messages of one side: a resume frame says which
+//! session the connection belongs to and the number of the message after it, the
+//! other side acks every message it got so the sender can forget about it. After
+//! a reconnect whatever was not acked yet is sent again on top of a new resume.
+//!
+//! Other servers frame their messages differently, `FrameFormat` covers the
+//! common length prefixes.
 
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Frames bigger than this are rejected instead of allocated, unless
+/// `MessageReader::with_max_message_size` says otherwise.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest payload of a ping or pong.
+pub const MAX_CONTROL_SIZE: usize = 125;
+
+const CONTROL: u32 = 1 << 31;
+const COMPRESSED: u32 = 1 << 30;
+const PING: u8 = 0;
+const PONG: u8 = 1;
+const RESUME: u8 = 2;
+const ACK: u8 = 3;
+
+/// Everything `MessageReader::next_frame` can read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Message(Vec<u8>),
+    /// Should be answered with `write_pong` and the same payload.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The messages that follow belong to the reliable session `session`, the
+    /// first of them is number `seq`. See `write_resume`.
+    Resume {
+        session: u64,
+        seq: u64,
+    },
+    /// Every message of the session numbered below this arrived.
+    Ack(u64),
+}
+
+/// Size of the length prefix of a `FrameFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixWidth {
+    U8,
+    U16,
+    U32,
+}
+
+/// Byte order of the length prefix of a `FrameFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+/// How the length in front of every message is written, for servers with framing
+/// of their own. The default is the 4 byte big endian length described above.
+///
+/// Control frames and compression only exist with the default prefix, in every
+/// other format the whole prefix is the length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub width: PrefixWidth,
+    pub endian: Endian,
+    /// Compress messages of at least this many bytes, `None` by default. Only messages
+    /// that get smaller are sent compressed.
+    ///
+    /// Compressed messages are only accepted when this is set, so both ends have to
+    /// agree on it. Without the `compression` feature messages are sent as they are
+    /// and compressed ones are rejected.
+    pub compress_above: Option<usize>,
+}
+
+impl Default for FrameFormat {
+    fn default() -> FrameFormat {
+        FrameFormat {
+            width: PrefixWidth::U32,
+            endian: Endian::Big,
+            compress_above: None,
+        }
+    }
+}
+
+impl FrameFormat {
+    /// Bytes in front of every message.
+    pub fn prefix_len(&self) -> usize {
+        match self.width {
+            PrefixWidth::U8 => 1,
+            PrefixWidth::U16 => 2,
+            PrefixWidth::U32 => 4,
+        }
+    }
+
+    /// Largest message the prefix can announce, at most `MAX_MESSAGE_SIZE`.
+    pub fn max_message_size(&self) -> usize {
+        match self.width {
+            PrefixWidth::U8 => u8::MAX as usize,
+            PrefixWidth::U16 => u16::MAX as usize,
+            PrefixWidth::U32 => MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Whether pings and pongs can be sent, only with the default prefix.
+    pub fn has_control_frames(&self) -> bool {
+        let default = FrameFormat::default();
+
+        (self.width, self.endian) == (default.width, default.endian)
+    }
+
+    /// Like the free `write_message`, in this format. Fails with
+    /// `ErrorKind::InvalidInput` if `data` is larger than `max_message_size`, or
+    /// for `compress_above` without the default prefix.
+    pub fn write_message(&self, mut writer: impl Write, data: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(self.prefix_len() + data.len());
+        self.push_message(&mut frame, data)?;
+
+        writer.write_all(&frame)
+    }
+
+    /// Append `data` as one frame to `frames`, to write several at once.
+    pub fn push_message(&self, frames: &mut Vec<u8>, data: &[u8]) -> io::Result<()> {
+        if data.len() > self.max_message_size() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if self.compress_above.is_some() && !self.has_control_frames() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "compression needs the default prefix",
+            ));
+        }
+
+        #[cfg(feature = "compression")]
+        if let Some(compressed) = self.compress(data)? {
+            frames.extend_from_slice(&(COMPRESSED | compressed.len() as u32).to_be_bytes());
+            frames.extend_from_slice(&compressed);
+            return Ok(());
+        }
+
+        let len = data.len() as u32;
+        match (self.width, self.endian) {
+            (PrefixWidth::U8, _) => frames.push(len as u8),
+            (PrefixWidth::U16, Endian::Big) => {
+                frames.extend_from_slice(&(len as u16).to_be_bytes())
+            }
+            (PrefixWidth::U16, Endian::Little) => {
+                frames.extend_from_slice(&(len as u16).to_le_bytes())
+            }
+            (PrefixWidth::U32, Endian::Big) => frames.extend_from_slice(&len.to_be_bytes()),
+            (PrefixWidth::U32, Endian::Little) => frames.extend_from_slice(&len.to_le_bytes()),
+        }
+        frames.extend_from_slice(data);
+
+        Ok(())
+    }
+
+    /// `data` deflated, `None` if it is too small to bother or did not get smaller.
+    #[cfg(feature = "compression")]
+    fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.compress_above {
+            Some(threshold) if data.len() >= threshold => {}
+            _ => return Ok(None),
+        }
+
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        Ok(Some(compressed).filter(|compressed| compressed.len() < data.len()))
+    }
+
+    /// The length `prefix_len` bytes of `prefix` say.
+    fn decode(&self, prefix: &[u8]) -> u32 {
+        match (self.width, self.endian) {
+            (PrefixWidth::U8, _) => prefix[0] as u32,
+            (PrefixWidth::U16, Endian::Big) => u16::from_be_bytes([prefix[0], prefix[1]]) as u32,
+            (PrefixWidth::U16, Endian::Little) => u16::from_le_bytes([prefix[0], prefix[1]]) as u32,
+            (PrefixWidth::U32, Endian::Big) => {
+                u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]])
+            }
+            (PrefixWidth::U32, Endian::Little) => {
+                u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]])
+            }
+        }
+    }
+}
+
+/// Write `data` as one frame, fails with `ErrorKind::InvalidInput` if it is larger
+/// than `MAX_MESSAGE_SIZE`.
+///
+/// The length and the message go out in a single write, same as control frames.
+pub fn write_message(writer: impl Write, data: &[u8]) -> io::Result<()> {
+    FrameFormat::default().write_message(writer, data)
+}
+
+/// Ask the other side for a pong, fails with `ErrorKind::InvalidInput` if `payload`
+/// is larger than `MAX_CONTROL_SIZE`.
+pub fn write_ping(writer: impl Write, payload: &[u8]) -> io::Result<()> {
+    write_control(writer, PING, payload)
+}
+
+pub fn write_pong(writer: impl Write, payload: &[u8]) -> io::Result<()> {
+    write_control(writer, PONG, payload)
+}
+
+/// Start or continue the reliable session `session`, the next message written is
+/// number `seq`. Messages the other side already got are skipped.
+pub fn write_resume(writer: impl Write, session: u64, seq: u64) -> io::Result<()> {
+    let mut payload = [0; 16];
+    payload[..8].copy_from_slice(&session.to_be_bytes());
+    payload[8..].copy_from_slice(&seq.to_be_bytes());
+
+    write_control(writer, RESUME, &payload)
+}
+
+/// Tell the other side every message of the session numbered below `seq` arrived.
+pub fn write_ack(writer: impl Write, seq: u64) -> io::Result<()> {
+    write_control(writer, ACK, &seq.to_be_bytes())
+}
+
+fn write_control(mut writer: impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_CONTROL_SIZE {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+
+    // In one write, so a control frame never ends up split around other writes
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.extend_from_slice(&(CONTROL | (1 + payload.len() as u32)).to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame)
+}
+
+/// Reads the frames written by `write_message`, `write_ping` and `write_pong`,
+/// or by `FrameFormat::write_message` of the format given to `with_format`.
+///
+/// Works with blocking and non-blocking streams and with read timeouts: bytes
+/// of a frame that did not fully arrive yet are kept until the next call.
 #[derive(Debug)]
-pub enum MessageReader {
-    Empty,
-    Amount(usize),
+pub struct MessageReader {
+    /// Bytes of the length prefix or, once that is known, of the message.
+    buffer: Vec<u8>,
+    len: Option<usize>,
+    /// Whether the frame being read is a control frame.
+    control: bool,
+    /// Whether the message being read has to be decompressed.
+    compressed: bool,
+    max_message_size: usize,
+    format: FrameFormat,
+}
+
+impl Default for MessageReader {
+    fn default() -> MessageReader {
+        MessageReader::with_max_message_size(MAX_MESSAGE_SIZE)
+    }
 }
 
 impl MessageReader {
     pub fn new() -> MessageReader {
-        MessageReader::Empty
+        MessageReader::default()
     }
 
-    pub fn next(&mut self, mut stream: impl std::io::Read) -> Result<Option<Vec<u8>>, ()> {
-        let mut bytes = [0 as u8; 255];
+    /// Reject frames larger than `max_message_size` instead of `MAX_MESSAGE_SIZE`.
+    pub fn with_max_message_size(max_message_size: usize) -> MessageReader {
+        MessageReader {
+            buffer: vec![],
+            len: None,
+            control: false,
+            compressed: false,
+            max_message_size,
+            format: FrameFormat::default(),
+        }
+    }
+
+    /// Read frames in `format` instead of the default one. Messages are still
+    /// limited to the maximum message size, before and after decompressing them.
+    pub fn with_format(self, format: FrameFormat) -> MessageReader {
+        MessageReader { format, ..self }
+    }
+
+    /// Read the next message into `buffer` instead of a new allocation, to hand
+    /// back the buffers of messages that were already handled.
+    ///
+    /// Ignored while the bytes of a message are being read.
+    pub fn reuse(&mut self, mut buffer: Vec<u8>) {
+        if self.len.is_none() && self.buffer.is_empty() {
+            buffer.clear();
+            self.buffer = buffer;
+        }
+    }
 
-        match self {
-            MessageReader::Empty => match stream.read_exact(&mut bytes[0..1]) {
-                Ok(_) => {
-                    *self = MessageReader::Amount(bytes[0] as usize);
-                    Ok(None)
+    /// Read until a whole message arrived, `None` if the stream would block or
+    /// time out first. Control frames are skipped, see `next_frame` to answer them.
+    ///
+    /// Fails with `ErrorKind::UnexpectedEof` once the stream ended and with
+    /// `ErrorKind::InvalidData` for frames larger than the maximum message size,
+    /// before anything is allocated for them.
+    pub fn next(&mut self, mut reader: impl Read) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.next_frame(&mut reader)? {
+                Some(Frame::Message(message)) => return Ok(Some(message)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like `next`, but hands out control frames too.
+    ///
+    /// Fails with `ErrorKind::InvalidData` for control frames of an unknown kind.
+    pub fn next_frame(&mut self, mut reader: impl Read) -> io::Result<Option<Frame>> {
+        loop {
+            let wanted = self.len.unwrap_or_else(|| self.format.prefix_len());
+
+            if self.buffer.len() < wanted {
+                let start = self.buffer.len();
+                self.buffer.resize(wanted, 0);
+
+                match reader.read(&mut self.buffer[start..]) {
+                    Ok(0) => {
+                        self.buffer.truncate(start);
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                    Ok(n) => self.buffer.truncate(start + n),
+                    Err(err)
+                        if err.kind() == ErrorKind::WouldBlock
+                            || err.kind() == ErrorKind::TimedOut =>
+                    {
+                        self.buffer.truncate(start);
+                        return Ok(None);
+                    }
+                    Err(err) if err.kind() == ErrorKind::Interrupted => self.buffer.truncate(start),
+                    Err(err) => {
+                        self.buffer.truncate(start);
+                        return Err(err);
+                    }
+                }
+                continue;
+            }
+
+            match self.len {
+                None => {
+                    let prefix = self.format.decode(&self.buffer);
+                    let control = self.format.has_control_frames() && prefix & CONTROL != 0;
+                    let compressed = self.format.has_control_frames()
+                        && self.format.compress_above.is_some()
+                        && !control
+                        && prefix & COMPRESSED != 0;
+                    let len = if control {
+                        (prefix & !CONTROL) as usize
+                    } else if compressed {
+                        (prefix & !COMPRESSED) as usize
+                    } else {
+                        prefix as usize
+                    };
+                    let max = if control {
+                        1 + MAX_CONTROL_SIZE
+                    } else {
+                        self.max_message_size
+                    };
+                    if len > max || (control && len == 0) {
+                        return Err(ErrorKind::InvalidData.into());
+                    }
+
+                    self.len = Some(len);
+                    self.control = control;
+                    self.compressed = compressed;
+                    self.buffer.clear();
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                Err(_err) => Err(()),
-            },
-            MessageReader::Amount(len) => match stream.read_exact(&mut bytes[0..*len]) {
-                Ok(_) => {
-                    let msg = bytes[0..*len].to_vec();
-                    *self = MessageReader::Empty;
-                    Ok(Some(msg))
+                Some(_) => {
+                    self.len = None;
+                    let mut data = std::mem::take(&mut self.buffer);
+                    if self.compressed {
+                        return self
+                            .decompress(&data)
+                            .map(|data| Some(Frame::Message(data)));
+                    }
+                    if !self.control {
+                        return Ok(Some(Frame::Message(data)));
+                    }
+
+                    let kind = data.remove(0);
+                    // Only called once the payload length was checked
+                    let number = |at: usize| {
+                        let mut bytes = [0; 8];
+                        bytes.copy_from_slice(&data[at..at + 8]);
+                        u64::from_be_bytes(bytes)
+                    };
+                    return match kind {
+                        PING => Ok(Some(Frame::Ping(data))),
+                        PONG => Ok(Some(Frame::Pong(data))),
+                        RESUME if data.len() == 16 => Ok(Some(Frame::Resume {
+                            session: number(0),
+                            seq: number(8),
+                        })),
+                        ACK if data.len() == 8 => Ok(Some(Frame::Ack(number(0)))),
+                        _ => Err(ErrorKind::InvalidData.into()),
+                    };
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                Err(_) => Err(()),
-            },
+            }
         }
     }
+
+    /// Inflate a compressed message, without ever holding more of it than the
+    /// maximum message size.
+    #[cfg(feature = "compression")]
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut message = vec![];
+        flate2::read::DeflateDecoder::new(data)
+            .take(self.max_message_size as u64 + 1)
+            .read_to_end(&mut message)?;
+        if message.len() > self.max_message_size {
+            return Err(ErrorKind::InvalidData.into());
+        }
+
+        Ok(message)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decompress(&self, _data: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "compressed message without the `compression` feature",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "compression")]
+    use std::convert::TryInto;
+
+    /// Hands out `chunk` bytes per read, with a `WouldBlock` before each of them,
+    /// like a nonblocking socket the data trickles into.
+    struct Trickle {
+        data: Vec<u8>,
+        chunk: usize,
+        blocked: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data.drain(..len);
+            Ok(len)
+        }
+    }
+
+    /// Every message in `data`, read `chunk` bytes at a time.
+    fn read_all(mut reader: MessageReader, data: Vec<u8>, chunk: usize) -> Vec<Vec<u8>> {
+        let mut trickle = Trickle {
+            data,
+            chunk,
+            blocked: false,
+        };
+        let mut messages = vec![];
+        while !trickle.data.is_empty() {
+            if let Some(message) = reader.next(&mut trickle).unwrap() {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn messages_larger_than_a_read_are_reassembled() {
+        let large: Vec<u8> = (0..10 * 1024).map(|i| i as u8).collect();
+        let mut data = vec![];
+        write_message(&mut data, &large).unwrap();
+        write_message(&mut data, b"small").unwrap();
+
+        for chunk in [1, 3, 1000, 4096] {
+            assert_eq!(
+                read_all(MessageReader::new(), data.clone(), chunk),
+                [large.clone(), b"small".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn frames_larger_than_the_maximum_are_rejected() {
+        let mut data = vec![];
+        write_message(&mut data, &[0; 1001]).unwrap();
+
+        let err = MessageReader::with_max_message_size(1000)
+            .next(&data[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_messages_round_trip() {
+        let format = FrameFormat {
+            compress_above: Some(100),
+            ..FrameFormat::default()
+        };
+        let small = b"below the threshold".to_vec();
+        let large = b"compressible ".repeat(1000);
+
+        let mut data = vec![];
+        format.write_message(&mut data, &small).unwrap();
+        let prefix = u32::from_be_bytes(data[..4].try_into().unwrap());
+        assert_eq!(prefix & COMPRESSED, 0);
+        assert_eq!(prefix as usize, small.len());
+
+        let start = data.len();
+        format.write_message(&mut data, &large).unwrap();
+        let prefix = u32::from_be_bytes(data[start..start + 4].try_into().unwrap());
+        assert_ne!(prefix & COMPRESSED, 0);
+        assert!(((prefix & !COMPRESSED) as usize) < large.len());
+
+        let reader = MessageReader::new().with_format(format);
+        assert_eq!(read_all(reader, data, 1000), [small, large]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn maximum_applies_after_decompressing() {
+        let format = FrameFormat {
+            compress_above: Some(100),
+            ..FrameFormat::default()
+        };
+        let mut data = vec![];
+        format.write_message(&mut data, &[0; 10_000]).unwrap();
+        // Small on the wire, too large once inflated
+        assert!(data.len() < 1000);
+
+        let err = MessageReader::with_max_message_size(1000)
+            .with_format(format)
+            .next(&data[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
 }
@@ -1,10 +1,28 @@
 use std::{
+    collections::HashMap,
+    io::Write,
     net::{TcpListener, TcpStream, ToSocketAddrs},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
-use super::protocol::MessageReader;
+use super::protocol::{write_ack, write_pong, Frame, FrameFormat, MessageReader};
+use crate::error::Error;
+
+/// How long a TCP client may leave its receive buffer full before sending to it
+/// fails and it is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a reliable session is remembered after its last message, for its
+/// client to resume it.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Most reliable sessions remembered at once, the one idle the longest is
+/// forgotten first.
+const MAX_SESSIONS: usize = 4096;
 
 pub struct Settings<F, F1, F2, S>
 where
@@ -21,27 +39,329 @@ where
     pub _marker: std::marker::PhantomData<S>,
 }
 
+/// The clients connected to `listen_with_server`, for sending to them from
+/// anywhere: a game loop on another thread, or any of the callbacks.
+///
+/// Clones share the clients and the callbacks.
+#[derive(Clone, Default)]
+pub struct Server {
+    clients: Arc<Clients>,
+}
+
+/// Identifies a connection for as long as the server runs, ids are never reused.
+///
+/// See `SocketHandle::client_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(u64);
+
+type LifecycleCallback = Arc<dyn Fn(ClientId) + Send + Sync>;
+
+#[derive(Default)]
+struct Clients {
+    next_id: AtomicU64,
+    connected: Mutex<HashMap<ClientId, Client>>,
+    on_connect: Mutex<Option<LifecycleCallback>>,
+    on_disconnect: Mutex<Option<LifecycleCallback>>,
+    /// Of TCP clients connecting from now on, the default one unless compressing.
+    format: Mutex<FrameFormat>,
+    /// Reliable sessions of TCP clients, with the number of the next message
+    /// expected and when the last one arrived. Kept after a disconnect, for the
+    /// client to resume them, until `SESSION_TIMEOUT`.
+    sessions: Mutex<HashMap<u64, (u64, Instant)>>,
+}
+
+/// Cloned out of `Clients::connected` to send to it, so a client that is slow to
+/// read doesn't hold up the others.
+#[derive(Clone)]
+enum Client {
+    WebSocket(ws::Sender),
+    Tcp {
+        /// Clone of the stream the connection's thread reads from.
+        stream: Arc<TcpStream>,
+        writing: Arc<Mutex<()>>,
+        /// The one the server had when the client connected.
+        format: FrameFormat,
+    },
+}
+
+impl Client {
+    /// Send `data` to WebSocket clients, its `frame` to TCP clients.
+    fn send(&self, frame: &[u8], data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Client::WebSocket(out) => out
+                .send(data)
+                .map_err(|_| std::io::ErrorKind::NotConnected.into()),
+            Client::Tcp {
+                stream, writing, ..
+            } => {
+                let _writing = writing.lock().unwrap();
+                Patient::new(stream).write_all(frame)
+            }
+        }
+    }
+
+    /// How messages to it are framed, `None` for WebSocket clients.
+    fn format(&self) -> Option<FrameFormat> {
+        match self {
+            Client::WebSocket(_) => None,
+            Client::Tcp { format, .. } => Some(*format),
+        }
+    }
+
+    /// The connection's thread notices and reports the disconnect.
+    fn disconnect(&self) {
+        match self {
+            Client::WebSocket(out) => {
+                let _ = out.close(ws::CloseCode::Normal);
+            }
+            Client::Tcp { stream, .. } => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Called with the id of every client once its connection is open, before its
+    /// first message.
+    pub fn on_connect(self, on_connect: impl Fn(ClientId) + Send + Sync + 'static) -> Server {
+        *self.clients.on_connect.lock().unwrap() = Some(Arc::new(on_connect));
+        self
+    }
+
+    /// Called with the id of every client that disconnected or was disconnected,
+    /// after the `on_disconnect` of `Settings`. Sending to it fails from then on.
+    pub fn on_disconnect(self, on_disconnect: impl Fn(ClientId) + Send + Sync + 'static) -> Server {
+        *self.clients.on_disconnect.lock().unwrap() = Some(Arc::new(on_disconnect));
+        self
+    }
+
+    /// Compress messages to and accept compressed messages from TCP clients, the
+    /// same as `FrameFormat::compress_above`. Clients have to be set up the same.
+    ///
+    /// Only affects connections opened afterwards, clients that are already
+    /// connected keep the format they connected with. WebSocket clients are left
+    /// alone.
+    pub fn compress_above(self, threshold: usize) -> Server {
+        self.clients.format.lock().unwrap().compress_above = Some(threshold);
+        self
+    }
+
+    /// Send `data` to every connected client, it is framed only once per format,
+    /// see `compress_above`.
+    ///
+    /// Waits for TCP clients that are slow to read, one at a time, other threads can
+    /// keep sending meanwhile. Clients it can't be sent to, or that take longer than
+    /// 5 seconds to read it, are disconnected. Returns how many clients it was sent
+    /// to.
+    pub fn broadcast(&self, data: &[u8]) -> usize {
+        if data.len() > self.format().max_message_size() {
+            return 0;
+        }
+
+        let clients: Vec<(ClientId, Client)> = self
+            .clients
+            .connected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, client)| (*id, client.clone()))
+            .collect();
+
+        // Clients that connected before `compress_above` changed have another format
+        let mut frames: Vec<(FrameFormat, Vec<u8>)> = vec![];
+        for format in clients.iter().filter_map(|(_, client)| client.format()) {
+            if frames.iter().all(|(framed, _)| *framed != format) {
+                let mut frame = vec![];
+                if format.push_message(&mut frame, data).is_err() {
+                    return 0;
+                }
+                frames.push((format, frame));
+            }
+        }
+        let frame_for = |client: &Client| match client.format() {
+            Some(format) => frames
+                .iter()
+                .find(|(framed, _)| *framed == format)
+                .map_or(&[][..], |(_, frame)| frame),
+            None => &[],
+        };
+
+        let mut sent = 0;
+        for (id, client) in &clients {
+            if client.send(frame_for(client), data).is_ok() {
+                sent += 1;
+            } else {
+                client.disconnect();
+                self.remove(*id);
+            }
+        }
+
+        sent
+    }
+
+    /// Send `data` to the client `id` only.
+    ///
+    /// Waits for a TCP client that is slow to read, like `broadcast`. Fails with
+    /// `NotConnected` if the client is gone. If it can't be sent to it is
+    /// disconnected, and the error is returned, `TimedOut` if it took longer than
+    /// 5 seconds to read it.
+    pub fn send_to(&self, id: ClientId, data: &[u8]) -> Result<(), Error> {
+        if data.len() > self.format().max_message_size() {
+            return Err(Error::MessageTooLarge);
+        }
+
+        let client = self
+            .clients
+            .connected
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected))?;
+        let mut frame = vec![];
+        if let Some(format) = client.format() {
+            format.push_message(&mut frame, data)?;
+        }
+
+        if let Err(err) = client.send(&frame, data) {
+            client.disconnect();
+            self.remove(id);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// How many clients are connected right now.
+    pub fn client_count(&self) -> usize {
+        self.clients.connected.lock().unwrap().len()
+    }
+
+    /// Whether message `seq` of `session` did not arrive before, remembering it did.
+    fn received(&self, session: u64, seq: u64) -> bool {
+        let now = Instant::now();
+        let mut sessions = self.clients.sessions.lock().unwrap();
+
+        // Only when one is added, so messages of known sessions stay cheap
+        if !sessions.contains_key(&session) {
+            sessions.retain(|_, (_, last)| now.duration_since(*last) < SESSION_TIMEOUT);
+            if sessions.len() >= MAX_SESSIONS {
+                let idlest = sessions
+                    .iter()
+                    .min_by_key(|(_, (_, last))| *last)
+                    .map(|(session, _)| *session);
+                if let Some(idlest) = idlest {
+                    sessions.remove(&idlest);
+                }
+            }
+        }
+
+        let (next, last) = sessions.entry(session).or_insert((0, now));
+        *last = now;
+        if seq < *next {
+            return false;
+        }
+        *next = seq + 1;
+
+        true
+    }
+
+    fn format(&self) -> FrameFormat {
+        *self.clients.format.lock().unwrap()
+    }
+
+    fn next_id(&self) -> ClientId {
+        ClientId(self.clients.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn add(&self, id: ClientId, client: Client) {
+        self.clients.connected.lock().unwrap().insert(id, client);
+
+        // Cloned out, so the callback can use the server
+        let on_connect = self.clients.on_connect.lock().unwrap().clone();
+        if let Some(on_connect) = on_connect {
+            on_connect(id);
+        }
+    }
+
+    /// Forget about `id`, does nothing if it is already gone.
+    fn remove(&self, id: ClientId) {
+        let removed = self.clients.connected.lock().unwrap().remove(&id);
+        if removed.is_some() {
+            self.disconnected(id);
+        }
+    }
+
+    fn disconnected(&self, id: ClientId) {
+        let on_disconnect = self.clients.on_disconnect.lock().unwrap().clone();
+        if let Some(on_disconnect) = on_disconnect {
+            on_disconnect(id);
+        }
+    }
+}
+
+/// Writes a frame to a nonblocking TCP stream, waiting whenever its send buffer is
+/// full instead of failing with `WouldBlock` halfway through. The whole frame has
+/// `WRITE_TIMEOUT`, however little the client reads at a time.
+struct Patient<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Patient<'a> {
+    fn new(stream: &'a TcpStream) -> Patient<'a> {
+        Patient {
+            stream,
+            deadline: Instant::now() + WRITE_TIMEOUT,
+        }
+    }
+}
+
+impl Write for Patient<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            match self.stream.write(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= self.deadline {
+                        return Err(std::io::ErrorKind::TimedOut.into());
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                written => return written,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
 enum Sender<'a> {
     WebSocket(&'a ws::Sender),
-    Tcp(&'a mut TcpStream),
+    Tcp(&'a mut TcpStream, &'a Mutex<()>, FrameFormat),
 }
 
 pub struct SocketHandle<'a> {
     sender: Sender<'a>,
+    id: ClientId,
     disconnect: bool,
 }
 
 impl<'a> Sender<'a> {
     fn send(&mut self, data: &[u8]) -> Option<()> {
-        use std::io::Write;
-
         match self {
             Sender::WebSocket(out) => {
                 out.send(data).ok()?;
             }
-            Sender::Tcp(stream) => {
-                stream.write(&[data.len() as u8]).ok()?;
-                stream.write(data).ok()?;
+            Sender::Tcp(stream, writing, format) => {
+                let _writing = writing.lock().unwrap();
+                format.write_message(Patient::new(stream), data).ok()?;
             }
         }
 
@@ -50,18 +370,26 @@ impl<'a> Sender<'a> {
 }
 
 impl<'a> SocketHandle<'a> {
-    fn new(sender: Sender<'a>) -> SocketHandle<'a> {
+    fn new(sender: Sender<'a>, id: ClientId) -> SocketHandle<'a> {
         SocketHandle {
             sender,
+            id,
             disconnect: false,
         }
     }
 
+    /// Id of the client this handle sends to, the same one `Server::on_connect` got.
+    pub fn client_id(&self) -> ClientId {
+        self.id
+    }
+
+    #[allow(clippy::result_unit_err)]
     pub fn send(&mut self, data: &[u8]) -> Result<(), ()> {
         self.sender.send(data).ok_or(())
     }
 
     #[cfg(feature = "nanoserde")]
+    #[allow(clippy::result_unit_err)]
     pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) -> Result<(), ()> {
         self.send(&nanoserde::SerBin::serialize_bin(data))
     }
@@ -79,6 +407,23 @@ where
     F1: Fn(&mut SocketHandle, &S) + Send + 'static,
     F2: Fn(&S) + Send + 'static,
     S: Default + Send + 'static,
+{
+    listen_with_server(Server::new(), tcp_addr, ws_addr, settings)
+}
+
+/// Like `listen`, with the clients tracked by `server`, see `Server::broadcast`.
+pub fn listen_with_server<A, A1, F, F1, F2, S>(
+    server: Server,
+    tcp_addr: A,
+    ws_addr: A1,
+    settings: Settings<F, F1, F2, S>,
+) where
+    A: ToSocketAddrs + std::fmt::Debug + Send,
+    A1: ToSocketAddrs + std::fmt::Debug + Send + 'static,
+    F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
+    F1: Fn(&mut SocketHandle, &S) + Send + 'static,
+    F2: Fn(&S) + Send + 'static,
+    S: Default + Send + 'static,
 {
     let on_message = Arc::new(Mutex::new(settings.on_message));
     let on_timer = Arc::new(Mutex::new(settings.on_timer));
@@ -92,6 +437,9 @@ where
         F2: Fn(&S) + Send + 'static,
     > {
         out: ws::Sender,
+        server: Server,
+        /// Only known to `server` once the connection is open.
+        id: ClientId,
         state: S,
         on_message: Arc<Mutex<F>>,
         on_timer: Arc<Mutex<F1>>,
@@ -108,7 +456,7 @@ where
     {
         fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
             let data = msg.into_data();
-            let mut handle = SocketHandle::new(Sender::WebSocket(&self.out));
+            let mut handle = SocketHandle::new(Sender::WebSocket(&self.out), self.id);
             (self.on_message.lock().unwrap())(&mut handle, &mut self.state, data);
             if handle.disconnect {
                 self.out.close(ws::CloseCode::Normal)?;
@@ -117,6 +465,8 @@ where
         }
 
         fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+            self.server
+                .add(self.id, Client::WebSocket(self.out.clone()));
             if let Some(timeout) = self.timeout {
                 self.out
                     .timeout(timeout.as_millis() as _, ws::util::Token(1))?;
@@ -126,9 +476,9 @@ where
 
         fn on_timeout(&mut self, _: ws::util::Token) -> ws::Result<()> {
             if let Some(timeout) = self.timeout {
-                let mut handle = SocketHandle::new(Sender::WebSocket(&self.out));
+                let mut handle = SocketHandle::new(Sender::WebSocket(&self.out), self.id);
                 (self.on_timer.lock().unwrap())(&mut handle, &self.state);
-                if handle.disconnect == false {
+                if !handle.disconnect {
                     self.out
                         .timeout(timeout.as_millis() as _, ws::util::Token(1))?;
                 } else {
@@ -140,6 +490,20 @@ where
 
         fn on_close(&mut self, _code: ws::CloseCode, _reason: &str) {
             (self.on_disconnect.lock().unwrap())(&self.state);
+            self.server.remove(self.id);
+        }
+    }
+
+    /// Connections that broke go away without `on_close`.
+    impl<
+            S: Default,
+            F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
+            F1: Fn(&mut SocketHandle, &S) + Send + 'static,
+            F2: Fn(&S) + Send + 'static,
+        > Drop for WsHandler<S, F, F1, F2>
+    {
+        fn drop(&mut self) {
+            self.server.remove(self.id);
         }
     }
 
@@ -147,6 +511,7 @@ where
         let on_message = on_message.clone();
         let on_timer = on_timer.clone();
         let on_disconnect = on_disconnect.clone();
+        let server = server.clone();
 
         move || {
             ws::Builder::new()
@@ -162,6 +527,8 @@ where
 
                     WsHandler {
                         out,
+                        server: server.clone(),
+                        id: server.next_id(),
                         state: S::default(),
                         on_message,
                         on_timer,
@@ -180,45 +547,163 @@ where
         let on_message = on_message.clone();
         let on_timer = on_timer.clone();
         let on_disconnect = on_disconnect.clone();
+        let server = server.clone();
 
         std::thread::spawn(move || {
             let mut stream = stream.unwrap();
             stream.set_nodelay(true).unwrap();
             stream.set_nonblocking(true).unwrap();
-            let mut message_reader = MessageReader::new();
+            let writing = Arc::new(Mutex::new(()));
+            let id = server.next_id();
+            let format = server.format();
+            server.add(
+                id,
+                Client::Tcp {
+                    stream: Arc::new(stream.try_clone().unwrap()),
+                    writing: writing.clone(),
+                    format,
+                },
+            );
+            let mut message_reader = MessageReader::new().with_format(format);
             let mut state = S::default();
+            // Reliable session of `TcpSocket`, with the number of the next message
+            let mut session = None;
 
             let mut time = Instant::now();
             loop {
-                match message_reader.next(&mut stream) {
-                    Ok(Some(message)) => {
-                        let mut handle = SocketHandle::new(Sender::Tcp(&mut stream));
-                        (on_message.lock().unwrap())(&mut handle, &mut state, message);
-                        if handle.disconnect {
+                match message_reader.next_frame(&mut stream) {
+                    Ok(Some(Frame::Message(message))) => {
+                        // Sent again after a reconnect, but handled already
+                        let fresh = match &mut session {
+                            Some((session, seq)) => {
+                                *seq += 1;
+                                server.received(*session, *seq - 1)
+                            }
+                            None => true,
+                        };
+
+                        let mut handle =
+                            SocketHandle::new(Sender::Tcp(&mut stream, &writing, format), id);
+                        if fresh {
+                            (on_message.lock().unwrap())(&mut handle, &mut state, message);
+                        }
+                        let disconnect = handle.disconnect;
+
+                        if let Some((_, seq)) = session {
+                            let _writing = writing.lock().unwrap();
+                            let _ = write_ack(Patient::new(&stream), seq);
+                        }
+                        if disconnect {
                             (on_disconnect.lock().unwrap())(&state);
-                            return;
+                            break;
                         }
                     }
-                    Ok(None) => {}
+                    // Heartbeats of `TcpSocket`, answering is enough
+                    Ok(Some(Frame::Ping(payload))) => {
+                        let _writing = writing.lock().unwrap();
+                        let _ = write_pong(Patient::new(&stream), &payload);
+                    }
+                    // Messages from now on are numbered from `seq`, some may be sent again
+                    Ok(Some(Frame::Resume { session: id, seq })) => session = Some((id, seq)),
+                    Ok(Some(Frame::Pong(_))) | Ok(Some(Frame::Ack(_))) | Ok(None) => {}
                     Err(_err) => {
                         (on_disconnect.lock().unwrap())(&state);
-                        return;
+                        break;
                     }
                 }
 
                 if let Some(timer) = timer {
                     if time.elapsed() >= timer {
                         time = Instant::now();
-                        let mut handle = SocketHandle::new(Sender::Tcp(&mut stream));
+                        let mut handle =
+                            SocketHandle::new(Sender::Tcp(&mut stream, &writing, format), id);
 
                         (on_timer.lock().unwrap())(&mut handle, &state);
                         if handle.disconnect {
                             (on_disconnect.lock().unwrap())(&state);
-                            return;
+                            break;
                         }
                     }
                 }
             }
+
+            server.remove(id);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn sessions_are_capped() {
+        let server = Server::new();
+        assert!(server.received(0, 0));
+        assert!(!server.received(0, 0));
+
+        for session in 1..=MAX_SESSIONS as u64 {
+            assert!(server.received(session, 0));
+        }
+
+        assert_eq!(server.clients.sessions.lock().unwrap().len(), MAX_SESSIONS);
+        // The idlest one was forgotten, its messages count as new
+        assert!(server.received(0, 0));
+    }
+
+    #[test]
+    fn patient_writes_frames_larger_than_the_send_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let frame = vec![7; 16 * 1024 * 1024];
+        let reader = std::thread::spawn(move || {
+            // Slower than the writer, so the send buffer fills up
+            std::thread::sleep(Duration::from_millis(100));
+            let mut received = vec![];
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        Patient::new(&stream).write_all(&frame).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        assert_eq!(reader.join().unwrap(), frame);
+    }
+
+    #[test]
+    fn slow_clients_dont_hold_up_the_others() {
+        let server = Server::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let id = server.next_id();
+        server.add(
+            id,
+            Client::Tcp {
+                stream: Arc::new(stream),
+                writing: Arc::default(),
+                format: server.format(),
+            },
+        );
+
+        // Never read, so the broadcast waits for it
+        let broadcast = std::thread::spawn({
+            let server = server.clone();
+            move || server.broadcast(&vec![7; 8 * 1024 * 1024])
+        });
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert_eq!(server.client_count(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        drop(client);
+        assert_eq!(broadcast.join().unwrap(), 0);
+        assert_eq!(server.client_count(), 0);
+    }
+}
@@ -0,0 +1,32 @@
+//! A self-signed certificate for `localhost` and `127.0.0.1`, to test TLS against
+//! local servers.
+
+use std::{net::TcpStream, sync::Arc};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+/// PEM encoded, the one to trust.
+pub const CERTIFICATE: &[u8] = include_bytes!("test_tls/localhost.pem");
+
+const KEY: &[u8] = include_bytes!("test_tls/localhost.key");
+
+/// The server side of a TLS connection over `stream`, the handshake happens with
+/// the first read or write.
+pub fn accept(stream: TcpStream) -> StreamOwned<ServerConnection, TcpStream> {
+    let certificates = CertificateDer::pem_slice_iter(CERTIFICATE)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_slice(KEY).unwrap();
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .unwrap();
+
+    StreamOwned::new(ServerConnection::new(Arc::new(config)).unwrap(), stream)
+}
@@ -18,12 +18,24 @@ pub(crate) mod js_web_socket {
     }
 
     impl WebSocket {
-        pub fn send_text(&self, text: &str) {
+        /// Send `data` as a binary message, never fails on web.
+        pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.send_bytes(data)
+        }
+
+        /// Send `text` as a text message, never fails on web.
+        pub fn send_str(&mut self, text: &str) -> Result<(), Error> {
+            self.send_text(text)
+        }
+
+        pub fn send_text(&self, text: &str) -> Result<(), Error> {
             unsafe { ws_send(JsObject::string(text)) };
+            Ok(())
         }
 
-        pub fn send_bytes(&self, data: &[u8]) {
+        pub fn send_bytes(&self, data: &[u8]) -> Result<(), Error> {
             unsafe { ws_send(JsObject::buffer(data)) };
+            Ok(())
         }
 
         pub fn try_recv(&mut self) -> Option<Vec<u8>> {
@@ -59,7 +71,10 @@ pub(crate) mod js_web_socket {
 mod pc_web_socket {
     use std::{
         net::ToSocketAddrs,
-        sync::{mpsc, Mutex},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc, Mutex,
+        },
     };
 
     use crate::error::Error;
@@ -67,6 +82,8 @@ mod pc_web_socket {
     pub struct WebSocket {
         sender: ws::Sender,
         rx: Mutex<mpsc::Receiver<Event>>,
+        /// Cleared by the ws thread once the connection is gone.
+        connected: Arc<AtomicBool>,
     }
 
     enum Event {
@@ -77,77 +94,106 @@ mod pc_web_socket {
     struct Client {
         out: ws::Sender,
         thread_out: mpsc::Sender<Event>,
+        connected: Arc<AtomicBool>,
     }
 
     impl ws::Handler for Client {
         fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
-            self.thread_out
-                .send(Event::Connect(self.out.clone()))
-                .unwrap();
+            // `connect` is waiting for it
+            let _ = self.thread_out.send(Event::Connect(self.out.clone()));
             Ok(())
         }
 
         fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
-            self.thread_out
-                .send(Event::Message(msg.into_data()))
-                .unwrap();
+            // The `WebSocket` being gone is no reason to fail here
+            let _ = self.thread_out.send(Event::Message(msg.into_data()));
             Ok(())
         }
 
-        fn on_close(&mut self, code: ws::CloseCode, _reason: &str) {
-            println!("closed {:?}", code);
+        fn on_close(&mut self, _code: ws::CloseCode, _reason: &str) {
+            self.connected.store(false, Ordering::Relaxed);
         }
 
-        fn on_error(&mut self, error: ws::Error) {
-            println!("{:?}", error);
+        /// The connection is closed right after, with `on_close` unless it broke.
+        fn on_error(&mut self, _error: ws::Error) {
+            self.connected.store(false, Ordering::Relaxed);
         }
     }
 
     impl WebSocket {
+        /// Blocks until the handshake is done, fails with a `ConnectionRefused`
+        /// IO error if it never finished.
         pub fn connect<A: ToSocketAddrs + std::fmt::Display>(addr: A) -> Result<WebSocket, Error> {
             let (tx, rx) = mpsc::channel();
             let ws_addr = format!("{}", addr);
-            std::thread::spawn(move || {
-                ws::connect(ws_addr, |out| Client {
-                    out,
-                    thread_out: tx.clone(),
-                })
-                .unwrap()
+            let connected = Arc::new(AtomicBool::new(true));
+            std::thread::spawn({
+                let connected = connected.clone();
+                move || {
+                    // A failure drops `tx`, which is reported below
+                    let _ = ws::connect(ws_addr, |out| Client {
+                        out,
+                        thread_out: tx.clone(),
+                        connected: connected.clone(),
+                    });
+                }
             });
 
             match rx.recv() {
                 Ok(Event::Connect(sender)) => Ok(WebSocket {
                     sender,
                     rx: Mutex::new(rx),
+                    connected,
                 }),
-                _ => panic!("Failed to connect websocket"),
+                _ => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()),
             }
         }
 
+        /// `false` once the connection was closed or broke.
         pub fn connected(&self) -> bool {
-            true
+            self.connected.load(Ordering::Relaxed)
         }
 
+        /// Send `data` as a binary message, pings of the server are answered on their own.
+        pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+            self.send_bytes(data)
+        }
+
+        /// Send `text` as a text message.
+        pub fn send_str(&mut self, text: &str) -> Result<(), Error> {
+            self.send_text(text)
+        }
+
+        /// Text and binary messages alike, `None` once disconnected and all of them
+        /// were received.
         pub fn try_recv(&mut self) -> Option<Vec<u8>> {
-            self.rx
-                .lock()
-                .unwrap()
-                .try_recv()
-                .ok()
-                .map(|event| match event {
-                    Event::Message(msg) => msg,
-                    _ => panic!(),
-                })
+            let rx = self.rx.lock().unwrap();
+            loop {
+                match rx.try_recv().ok()? {
+                    Event::Message(msg) => return Some(msg),
+                    // Only ever the first one, taken by `connect`
+                    Event::Connect(_) => {}
+                }
+            }
+        }
+
+        /// Fails with a `NotConnected` IO error once disconnected.
+        pub fn send_text(&self, text: &str) -> Result<(), Error> {
+            self.send_message(ws::Message::text(text))
         }
 
-        pub fn send_text(&self, text: &str) {
-            self.sender.send(ws::Message::text(text)).unwrap();
+        /// Fails with a `NotConnected` IO error once disconnected.
+        pub fn send_bytes(&self, data: &[u8]) -> Result<(), Error> {
+            self.send_message(ws::Message::Binary(data.to_vec()))
         }
 
-        pub fn send_bytes(&self, data: &[u8]) {
-            self.sender
-                .send(ws::Message::Binary(data.to_vec()))
-                .unwrap();
+        fn send_message(&self, message: ws::Message) -> Result<(), Error> {
+            if !self.connected() {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+            }
+            self.sender.send(message).map_err(std::io::Error::other)?;
+
+            Ok(())
         }
     }
 }
@@ -156,3 +202,119 @@ mod pc_web_socket {
 pub use js_web_socket::WebSocket;
 #[cfg(not(target_arch = "wasm32"))]
 pub use pc_web_socket::WebSocket;
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::mpsc::{self, Receiver, Sender},
+        time::{Duration, Instant},
+    };
+
+    /// Pings every client once it connects, then echoes its messages and tells about
+    /// them and the pongs. Closes the connection when told "close".
+    struct Server {
+        out: ws::Sender,
+        events: Sender<ws::Message>,
+        pongs: Sender<Vec<u8>>,
+    }
+
+    impl ws::Handler for Server {
+        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+            self.out.ping(b"ping".to_vec())
+        }
+
+        fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+            let _ = self.events.send(msg.clone());
+            if msg.as_text().ok() == Some("close") {
+                return self.out.close(ws::CloseCode::Normal);
+            }
+            self.out.send(msg)
+        }
+
+        fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+            if frame.opcode() == ws::OpCode::Pong {
+                let _ = self.pongs.send(frame.payload().clone());
+            }
+            Ok(Some(frame))
+        }
+    }
+
+    /// Url of a `Server`, what it received and the pongs it got.
+    fn serve() -> (String, Receiver<ws::Message>, Receiver<Vec<u8>>) {
+        let (events_tx, events) = mpsc::channel();
+        let (pongs_tx, pongs) = mpsc::channel();
+        let server = ws::WebSocket::new(move |out| Server {
+            out,
+            events: events_tx.clone(),
+            pongs: pongs_tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _ = server.run();
+        });
+
+        (url, events, pongs)
+    }
+
+    fn recv(socket: &mut WebSocket) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(message) = socket.try_recv() {
+                return Some(message);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn text_and_binary_messages_round_trip() {
+        let (url, events, _pongs) = serve();
+        let mut socket = WebSocket::connect(url).unwrap();
+
+        socket.send_str("hello").unwrap();
+        socket.send(&[0, 255]).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ws::Message::text("hello")
+        );
+        assert_eq!(
+            events.recv_timeout(timeout).unwrap(),
+            ws::Message::Binary(vec![0, 255])
+        );
+        assert_eq!(recv(&mut socket).unwrap(), b"hello");
+        assert_eq!(recv(&mut socket).unwrap(), [0, 255]);
+    }
+
+    #[test]
+    fn pings_are_answered() {
+        let (url, _events, pongs) = serve();
+        let _socket = WebSocket::connect(url).unwrap();
+
+        assert_eq!(pongs.recv_timeout(Duration::from_secs(5)).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn closing_disconnects_without_panicking() {
+        let (url, _events, _pongs) = serve();
+        let mut socket = WebSocket::connect(url).unwrap();
+        socket.send_str("close").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while socket.connected() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(socket.try_recv(), None);
+        assert!(socket.send(b"gone").is_err());
+        assert!(socket.send_text("gone").is_err());
+    }
+}